@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{command::CommandSpec, effect::Pattern};
+
+/// What a binding does when it fires.
+///
+/// In the config an action is a table tagged by `action`, e.g.
+/// `{ action = "command", cmd = "/usr/bin/mpc", args = ["toggle"] }`.
+/// Actions without parameters can be given as a bare name, `"poweroff"`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Action {
+    /// Does nothing, for bindings that only use gestures.
+    None,
+    #[serde(rename = "poweroff", alias = "shutdown")]
+    PowerOff,
+    Restart,
+    Halt,
+    /// Drive the binding's own output line high.
+    #[serde(rename = "seton")]
+    SetOn,
+    #[serde(rename = "setoff")]
+    SetOff,
+    /// Drive the line of an output binding high.
+    Set {
+        output: u32,
+    },
+    /// Drive the line of an output binding low.
+    Clear {
+        output: u32,
+    },
+    Toggle {
+        output: u32,
+    },
+    /// Drive the line of an output binding high for `duration_ms`, then low.
+    Pulse {
+        output: u32,
+        duration_ms: u64,
+    },
+    /// Spawn an external program.
+    Command(CommandSpec),
+    /// Run a pattern on an output line, replacing the one running there.
+    Effect {
+        /// Pin of the output binding to run on. Unset on an output binding
+        /// means its own line.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        output: Option<u32>,
+        #[serde(flatten)]
+        pattern: Pattern,
+    },
+    /// Set the duty cycle of a `pwm_output` in percent, and its period if
+    /// given, e.g. to change the pitch of a buzzer.
+    PwmSet {
+        output: String,
+        duty: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        period_ns: Option<u64>,
+    },
+    /// Move the duty cycle of a `pwm_output` to `duty` percent over `duration_ms`.
+    PwmFade {
+        output: String,
+        duty: u8,
+        duration_ms: u64,
+    },
+    /// Toggle between play and pause, starts playback when stopped.
+    MpdToggle,
+    MpdStop,
+    MpdNext,
+    MpdPrevious,
+    MpdVolumeUp {
+        #[serde(default = "default_volume_step")]
+        step: u8,
+    },
+    MpdVolumeDown {
+        #[serde(default = "default_volume_step")]
+        step: u8,
+    },
+    /// Replace the queue with a stored playlist and play it.
+    MpdPlaylist {
+        name: String,
+    },
+    MprisPlayPause,
+    MprisNext,
+    MprisPrevious,
+    /// Seek by `offset_ms`, backwards if negative.
+    MprisSeek {
+        offset_ms: i64,
+    },
+    /// Set the player volume in percent.
+    MprisVolume {
+        volume: u8,
+    },
+}
+
+impl Action {
+    /// The name the action is written as in the config, e.g. `mpd-next`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::None => "none",
+            Action::PowerOff => "poweroff",
+            Action::Restart => "restart",
+            Action::Halt => "halt",
+            Action::SetOn => "seton",
+            Action::SetOff => "setoff",
+            Action::Set { .. } => "set",
+            Action::Clear { .. } => "clear",
+            Action::Toggle { .. } => "toggle",
+            Action::Pulse { .. } => "pulse",
+            Action::Command(_) => "command",
+            Action::Effect { .. } => "effect",
+            Action::PwmSet { .. } => "pwm-set",
+            Action::PwmFade { .. } => "pwm-fade",
+            Action::MpdToggle => "mpd-toggle",
+            Action::MpdStop => "mpd-stop",
+            Action::MpdNext => "mpd-next",
+            Action::MpdPrevious => "mpd-previous",
+            Action::MpdVolumeUp { .. } => "mpd-volume-up",
+            Action::MpdVolumeDown { .. } => "mpd-volume-down",
+            Action::MpdPlaylist { .. } => "mpd-playlist",
+            Action::MprisPlayPause => "mpris-play-pause",
+            Action::MprisNext => "mpris-next",
+            Action::MprisPrevious => "mpris-previous",
+            Action::MprisSeek { .. } => "mpris-seek",
+            Action::MprisVolume { .. } => "mpris-volume",
+        }
+    }
+}
+
+fn default_volume_step() -> u8 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_the_config_tags() {
+        let actions: Vec<Action> = serde_json::from_str(
+            r#"[
+                {"action": "none"}, {"action": "poweroff"}, {"action": "restart"},
+                {"action": "halt"}, {"action": "seton"}, {"action": "setoff"},
+                {"action": "set", "output": 4}, {"action": "clear", "output": 4},
+                {"action": "toggle", "output": 4},
+                {"action": "pulse", "output": 4, "duration_ms": 100},
+                {"action": "command", "cmd": "true"},
+                {"action": "effect", "pattern": "off"},
+                {"action": "pwm-set", "output": "backlight", "duty": 50},
+                {"action": "pwm-fade", "output": "backlight", "duty": 50, "duration_ms": 100},
+                {"action": "mpd-toggle"}, {"action": "mpd-stop"}, {"action": "mpd-next"},
+                {"action": "mpd-previous"}, {"action": "mpd-volume-up"},
+                {"action": "mpd-volume-down"}, {"action": "mpd-playlist", "name": "radio"},
+                {"action": "mpris-play-pause"}, {"action": "mpris-next"},
+                {"action": "mpris-previous"}, {"action": "mpris-seek", "offset_ms": 5000},
+                {"action": "mpris-volume", "volume": 50}
+            ]"#,
+        )
+        .unwrap();
+        for action in actions {
+            let written = serde_json::to_value(&action).unwrap();
+            assert_eq!(written["action"], action.name(), "{:?}", action);
+        }
+    }
+}
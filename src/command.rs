@@ -0,0 +1,101 @@
+//! The `command` action: run an external program for a binding.
+
+use std::{collections::BTreeMap, path::PathBuf, process::Stdio, time::Duration};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, time};
+
+use crate::{input::GestureEvent, Void};
+
+/// ```toml
+/// gpio22 = { action = "command", cmd = "/usr/local/bin/skip", args = ["--next"], cwd = "/srv/music", env = { PLAYER = "mpd" }, timeout_ms = 5000 }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub cmd: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Added to the environment radIO runs in.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Kill the program if it is still running after this long.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Describe the event that fired the binding to the program:
+///
+/// - `RADIO_PIN`: GPIO number
+/// - `RADIO_EDGE`: logical edge, `rising` or `falling`, unset if a timer
+///   completed the press
+/// - `RADIO_TIMESTAMP`: kernel timestamp of the event in nanoseconds
+/// - `RADIO_PRESS`: `short`, `long` or `double`
+fn event_env(line: u32, event: Option<&GestureEvent>) -> Vec<(&'static str, String)> {
+    let mut env = vec![("RADIO_PIN", line.to_string())];
+    if let Some(event) = event {
+        if let Some(edge) = event.edge {
+            env.push(("RADIO_EDGE", edge.name().to_string()));
+        }
+        env.push(("RADIO_TIMESTAMP", event.timestamp.to_string()));
+        env.push(("RADIO_PRESS", event.gesture.name().to_string()));
+    }
+    env
+}
+
+/// Spawn the program and log its output and exit status once it is done.
+/// This doesn't wait for the program, so a slow script can't stall the
+/// event loop.
+pub fn run_command(spec: &CommandSpec, line: u32, event: Option<&GestureEvent>) -> Void {
+    info!("Run {} {:?}", spec.cmd, spec.args);
+    let mut command = Command::new(&spec.cmd);
+    command
+        .args(&spec.args)
+        .envs(&spec.env)
+        .envs(event_env(line, event))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(cwd) = &spec.cwd {
+        command.current_dir(cwd);
+    }
+    let child = command.spawn()?;
+
+    let cmd = spec.cmd.clone();
+    let timeout = spec.timeout_ms.map(Duration::from_millis);
+    tokio::spawn(async move {
+        let output = match timeout {
+            Some(timeout) => match time::timeout(timeout, child.wait_with_output()).await {
+                Ok(output) => output,
+                Err(_) => {
+                    // Dropping the future dropped the child, which kills it.
+                    warn!("{} killed after {:?}", cmd, timeout);
+                    return;
+                }
+            },
+            None => child.wait_with_output().await,
+        };
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                error!("Failed to wait for {}: {}", cmd, e);
+                return;
+            }
+        };
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            info!("{}: {}", cmd, line);
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            warn!("{}: {}", cmd, line);
+        }
+        if output.status.success() {
+            debug!("{} finished", cmd);
+        } else {
+            warn!("{} exited with {}", cmd, output.status);
+        }
+    });
+    Ok(())
+}
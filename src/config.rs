@@ -0,0 +1,453 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::Read,
+    iter,
+    marker::PhantomData,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{info, warn, LevelFilter};
+use serde::{
+    de::{
+        self,
+        value::{MapAccessDeserializer, MapDeserializer},
+        MapAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize,
+};
+
+use crate::{
+    action::Action,
+    dbus::DbusConfig,
+    encoder::EncoderConfig,
+    gpio::{Bias, LineConfig},
+    http::HttpConfig,
+    input::{EdgeSelect, Gesture, GestureConfig},
+    mpd::MpdConfig,
+    mpris::MprisConfig,
+    mqtt::MqttConfig,
+    power::PowerBackend,
+    pwm::DEFAULT_PWM_ROOT,
+    socket::ControlConfig,
+    watchdog::WatchdogConfig,
+};
+
+pub const DEFAULT_CHIP: &str = "/dev/gpiochip0";
+pub const CFGPATH: &str = "/etc/radio.conf";
+pub const DEFAULT_HOLD_MS: u64 = 800;
+pub const DEFAULT_DOUBLE_MS: u64 = 300;
+
+/// An input pin, either bound straight to an action or with extra settings:
+///
+/// ```toml
+/// [input_binding]
+/// gpio17 = "poweroff"
+/// gpio27 = { action = "restart", debounce_ms = 80 }
+/// gpio22 = { action = "command", cmd = "/usr/bin/mpc", args = ["toggle"], long_press = "poweroff" }
+/// gpio23 = { action = "none", double_press = "restart", active_low = false, bias = "pull-down" }
+/// gpio24 = { action = "pulse", output = 12, duration_ms = 200 }
+/// ```
+///
+/// A binding without gestures fires on the edges selected by `edge`, by
+/// default as soon as the button goes down. Once a long or double press is
+/// bound, the short press fires on release instead, after the double press
+/// window has passed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputBinding {
+    /// Short press action.
+    #[serde(flatten)]
+    pub action: Action,
+    /// How long the button has to be held for a long press.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_ms: Option<u64>,
+    /// Maximum gap between the release of the first press and the second press.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub double_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edge: Option<EdgeSelect>,
+    /// Buttons usually pull the line to ground, so inputs default to active low.
+    #[serde(default = "active_low_input")]
+    pub active_low: bool,
+    #[serde(default)]
+    pub bias: Bias,
+    // Tables have to come after plain values when written back as TOML.
+    #[serde(
+        default,
+        deserialize_with = "optional_action",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub long_press: Option<Action>,
+    #[serde(
+        default,
+        deserialize_with = "optional_action",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub double_press: Option<Action>,
+}
+
+impl InputBinding {
+    pub fn action_for(&self, gesture: Gesture) -> Option<&Action> {
+        match gesture {
+            Gesture::Short => Some(&self.action),
+            Gesture::Long => self.long_press.as_ref(),
+            Gesture::Double => self.double_press.as_ref(),
+        }
+    }
+
+    pub fn gesture_config(&self) -> GestureConfig {
+        GestureConfig {
+            long: self
+                .long_press
+                .as_ref()
+                .map(|_| Duration::from_millis(self.hold_ms.unwrap_or(DEFAULT_HOLD_MS))),
+            double: self
+                .double_press
+                .as_ref()
+                .map(|_| Duration::from_millis(self.double_ms.unwrap_or(DEFAULT_DOUBLE_MS))),
+            trigger: self.edge.unwrap_or_default(),
+        }
+    }
+
+    pub fn line_config(&self) -> LineConfig {
+        LineConfig {
+            active_low: self.active_low,
+            bias: self.bias,
+        }
+    }
+}
+
+/// An output pin and the action run on it at startup:
+///
+/// ```toml
+/// [output_binding]
+/// gpio4 = "seton"
+/// gpio5 = { action = "setoff", active_low = true, on_exit = false }
+/// gpio6 = { action = "effect", pattern = "breathe", period_ms = 4000 }
+/// ```
+///
+/// Inputs can change the pattern later, e.g.
+/// `{ action = "effect", output = 6, pattern = "flash", count = 3 }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OutputBinding {
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(default)]
+    pub active_low: bool,
+    /// Level to drive the line to on a clean shutdown. Unset leaves it as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_exit: Option<bool>,
+    #[serde(default)]
+    pub bias: Bias,
+}
+
+impl OutputBinding {
+    pub fn line_config(&self) -> LineConfig {
+        LineConfig {
+            active_low: self.active_low,
+            bias: self.bias,
+        }
+    }
+}
+
+/// A rotary encoder on two lines, with an optional push switch:
+///
+/// ```toml
+/// [encoder.volume]
+/// pin_a = 5
+/// pin_b = 6
+/// switch_pin = 13
+/// accel_ms = 60
+/// clockwise = { action = "mpd-volume-up", step = 2 }
+/// counter_clockwise = { action = "mpd-volume-down", step = 2 }
+/// press = "mpd-toggle"
+/// ```
+///
+/// An accelerated detent runs its action once per step.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncoderBinding {
+    pub pin_a: u32,
+    pub pin_b: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub switch_pin: Option<u32>,
+    /// Quarter steps per detent: 1, 2 or 4.
+    #[serde(default = "default_steps_per_detent")]
+    pub steps_per_detent: u8,
+    /// Detents closer together than this count as one more step than the
+    /// one before, up to `accel_max`. Unset turns acceleration off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accel_ms: Option<u64>,
+    #[serde(default = "default_accel_max")]
+    pub accel_max: u32,
+    /// Applies to all lines of the encoder. Encoders bounce less than
+    /// buttons, so this doesn't fall back to the global `debounce_ms`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+    #[serde(default = "active_low_input")]
+    pub active_low: bool,
+    #[serde(default)]
+    pub bias: Bias,
+    #[serde(deserialize_with = "action")]
+    pub clockwise: Action,
+    #[serde(deserialize_with = "action")]
+    pub counter_clockwise: Action,
+    #[serde(
+        default,
+        deserialize_with = "optional_action",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub press: Option<Action>,
+}
+
+impl EncoderBinding {
+    pub fn encoder_config(&self) -> EncoderConfig {
+        EncoderConfig {
+            pin_a: self.pin_a,
+            pin_b: self.pin_b,
+            steps_per_detent: self.steps_per_detent,
+            accel: self.accel_ms.map(Duration::from_millis),
+            accel_max: self.accel_max,
+        }
+    }
+
+    pub fn line_config(&self) -> LineConfig {
+        LineConfig {
+            active_low: self.active_low,
+            bias: self.bias,
+        }
+    }
+}
+
+/// A hardware PWM channel, addressed by name from the `pwm-set` and
+/// `pwm-fade` actions:
+///
+/// ```toml
+/// [pwm_output.backlight]
+/// chip = 0
+/// channel = 1
+/// period_ns = 1000000
+/// duty = 80
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PwmBinding {
+    /// `N` of `/sys/class/pwm/pwmchipN`.
+    pub chip: u32,
+    pub channel: u32,
+    pub period_ns: u64,
+    /// Duty cycle in percent at startup.
+    #[serde(default)]
+    pub duty: u8,
+    /// Duty cycle to set on a clean shutdown. Unset leaves it as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_exit: Option<u8>,
+}
+
+fn active_low_input() -> bool {
+    true
+}
+
+fn default_steps_per_detent() -> u8 {
+    4
+}
+
+fn default_accel_max() -> u32 {
+    5
+}
+
+/// Accepts a bare action name as shorthand for `{ action = "<name>" }`.
+struct Shorthand<T>(T);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Shorthand<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ShorthandVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for ShorthandVisitor<T> {
+            type Value = T;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an action name or a table with an `action` key")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<T, E> {
+                T::deserialize(MapDeserializer::new(iter::once(("action", name))))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+                T::deserialize(MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer
+            .deserialize_any(ShorthandVisitor(PhantomData))
+            .map(Shorthand)
+    }
+}
+
+fn action<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Action, D::Error> {
+    Shorthand::deserialize(deserializer).map(|action| action.0)
+}
+
+fn optional_action<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Action>, D::Error> {
+    let action = Option::<Shorthand<Action>>::deserialize(deserializer)?;
+    Ok(action.map(|action| action.0))
+}
+
+fn bindings<'de, D, T>(deserializer: D) -> Result<HashMap<String, T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let bindings = HashMap::<String, Shorthand<T>>::deserialize(deserializer)?;
+    Ok(bindings.into_iter().map(|(k, v)| (k, v.0)).collect())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppConfig {
+    pub master_chip: String,
+    pub log_level: u8,
+    /// Debounce window for every input without its own `debounce_ms`. 0 disables it.
+    #[serde(default)]
+    pub debounce_ms: u64,
+    #[serde(default)]
+    pub power_backend: PowerBackend,
+    /// Where the sysfs PWM class lives.
+    #[serde(default = "default_pwm_root")]
+    pub pwm_root: PathBuf,
+    #[serde(default)]
+    pub mpd: MpdConfig,
+    #[serde(default)]
+    pub mpris: MprisConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub dbus: DbusConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+    #[serde(deserialize_with = "bindings")]
+    pub input_binding: HashMap<String, InputBinding>,
+    #[serde(deserialize_with = "bindings")]
+    pub output_binding: HashMap<String, OutputBinding>,
+    /// Rotary encoders by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub encoder: HashMap<String, EncoderBinding>,
+    /// Hardware PWM channels by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub pwm_output: HashMap<String, PwmBinding>,
+}
+
+fn default_pwm_root() -> PathBuf {
+    PathBuf::from(DEFAULT_PWM_ROOT)
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            master_chip: DEFAULT_CHIP.to_string(),
+            log_level: 3,
+            debounce_ms: 0,
+            power_backend: PowerBackend::default(),
+            pwm_root: default_pwm_root(),
+            mpd: MpdConfig::default(),
+            mpris: MprisConfig::default(),
+            control: ControlConfig::default(),
+            dbus: DbusConfig::default(),
+            mqtt: MqttConfig::default(),
+            http: HttpConfig::default(),
+            watchdog: WatchdogConfig::default(),
+            input_binding: HashMap::new(),
+            output_binding: HashMap::new(),
+            encoder: HashMap::new(),
+            pwm_output: HashMap::new(),
+        }
+    }
+}
+
+impl AppConfig {
+    /// A copy that is safe to hand out, with every password masked.
+    pub fn redacted(&self) -> AppConfig {
+        let mask = |secret: &Option<String>| secret.as_ref().map(|_| "********".to_string());
+        let mut cfg = self.clone();
+        cfg.mpd.password = mask(&self.mpd.password);
+        cfg.mqtt.password = mask(&self.mqtt.password);
+        cfg
+    }
+}
+
+pub fn log_level_to_enum(input: u8) -> LevelFilter {
+    match input {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        5 => LevelFilter::Trace,
+        _ => LevelFilter::Error,
+    }
+}
+
+fn sanitise_key(key: &str) -> String {
+    key.replace("gpio", "").trim().to_string()
+}
+
+/// The GPIO number a binding key like `gpio17` refers to.
+pub fn pin_number(key: &str) -> Result<u32, ParseIntError> {
+    sanitise_key(key).parse()
+}
+
+fn sanitise_keys<T>(bindings: HashMap<String, T>) -> HashMap<String, T> {
+    bindings
+        .into_iter()
+        .map(|(k, v)| (sanitise_key(&k), v))
+        .collect()
+}
+
+pub fn sanitise_gpio_names(cfg: AppConfig) -> AppConfig {
+    AppConfig {
+        input_binding: sanitise_keys(cfg.input_binding),
+        output_binding: sanitise_keys(cfg.output_binding),
+        ..cfg
+    }
+}
+
+/// Read the config at `path`, along with the TOML document it was parsed
+/// from, in which [`validate`](crate::validate::validate) looks for keys
+/// that nothing reads.
+pub fn read_config(path: &Path) -> anyhow::Result<(AppConfig, toml::Value)> {
+    let mut buffer = String::default();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut buffer))
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // Parsed straight from the text, so errors keep their line numbers.
+    let appcfg: AppConfig =
+        toml::from_str(&buffer).with_context(|| format!("Failed to parse {}", path.display()))?;
+    let document = toml::from_str(&buffer)?;
+    Ok((appcfg, document))
+}
+
+/// Like [`read_config`], but writes the default config to `path` first if
+/// there is no file yet.
+pub fn load_config(path: &Path) -> anyhow::Result<(AppConfig, toml::Value)> {
+    if path.exists() {
+        return read_config(path);
+    }
+    warn!("{} does not exist", path.display());
+    let defaultcfg = AppConfig::default();
+    info!("Writing default config to {}", path.display());
+    fs::write(path, default_config_toml()?)?;
+    let document = toml::Value::try_from(&defaultcfg)?;
+    Ok((defaultcfg, document))
+}
+
+pub fn default_config_toml() -> anyhow::Result<String> {
+    Ok(toml::to_string_pretty(&AppConfig::default())?)
+}
@@ -0,0 +1,207 @@
+//! The interface the daemon offers to everything that talks to it from the
+//! outside: requests that run inside the event loop, and a feed of what
+//! happens on the lines.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{
+    action::Action,
+    config::AppConfig,
+    encoder::Direction,
+    gpio::{Edge, EdgeEvent},
+    input::{Gesture, GestureEvent},
+};
+
+/// Events a subscriber may fall behind by before it misses some.
+const EVENT_BACKLOG: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// The applied config.
+    Bindings,
+    /// Current levels of the held lines.
+    Levels,
+    /// Run the action bound to a gesture of an input, as if it was pressed.
+    /// An encoder is addressed by its name or one of its pins: a `turn`
+    /// runs its `clockwise` or `counter_clockwise` action once, no `turn`
+    /// its `press`.
+    Trigger {
+        /// Key of the input binding, `gpio17` or `17`, or an encoder.
+        input: String,
+        #[serde(default)]
+        gesture: Gesture,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        turn: Option<Direction>,
+    },
+    /// Drive an output binding.
+    Set { output: u32, value: bool },
+    /// Follow the events on all lines.
+    Subscribe,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Error {
+        message: String,
+    },
+    Bindings {
+        config: Box<AppConfig>,
+    },
+    Levels {
+        /// Logical level after the last edge seen on each input.
+        inputs: BTreeMap<u32, bool>,
+        outputs: BTreeMap<u32, bool>,
+        /// Duty cycle of each PWM output in percent.
+        pwm: BTreeMap<String, u8>,
+    },
+    Event(Event),
+}
+
+impl From<anyhow::Result<()>> for Response {
+    fn from(result: anyhow::Result<()>) -> Self {
+        match result {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error {
+                message: format!("{:#}", e),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A debounced edge on an input line.
+    Edge {
+        pin: u32,
+        edge: Edge,
+        timestamp: u64,
+    },
+    /// A recognised gesture and what its binding did about it.
+    Press {
+        pin: u32,
+        gesture: Gesture,
+        /// The edge that completed the gesture, unset if a timer did.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        edge: Option<Edge>,
+        timestamp: u64,
+        /// Name of the action run, unset if the gesture isn't bound.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<String>,
+        /// Why the action failed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// Collects what happens on the input lines for the control interfaces.
+#[derive(Clone)]
+pub struct Monitor {
+    events: broadcast::Sender<Event>,
+    inputs: Arc<Mutex<HashMap<u32, bool>>>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor {
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            inputs: Arc::default(),
+        }
+    }
+}
+
+impl Monitor {
+    pub fn edge(&self, event: &EdgeEvent) {
+        self.inputs
+            .lock()
+            .unwrap()
+            .insert(event.line, event.edge == Edge::Rising);
+        self.publish(Event::Edge {
+            pin: event.line,
+            edge: event.edge,
+            timestamp: event.timestamp,
+        });
+    }
+
+    pub fn press(
+        &self,
+        event: &GestureEvent,
+        action: Option<&Action>,
+        result: &anyhow::Result<()>,
+    ) {
+        self.publish(Event::Press {
+            pin: event.line,
+            gesture: event.gesture,
+            edge: event.edge,
+            timestamp: event.timestamp,
+            action: action.map(|action| action.name().to_string()),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+    }
+
+    pub fn input_levels(&self) -> BTreeMap<u32, bool> {
+        self.inputs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(line, level)| (*line, *level))
+            .collect()
+    }
+
+    /// Stop reporting the level of a released line.
+    pub fn forget(&self, line: u32) {
+        self.inputs.lock().unwrap().remove(&line);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, event: Event) {
+        // Nobody listening is fine.
+        let _ = self.events.send(event);
+    }
+}
+
+pub type Reply = oneshot::Sender<Response>;
+
+/// A handle to the running daemon. Cheap to clone, one per client.
+#[derive(Clone)]
+pub struct Control {
+    requests: mpsc::UnboundedSender<(Request, Reply)>,
+    monitor: Monitor,
+}
+
+impl Control {
+    pub fn new(requests: mpsc::UnboundedSender<(Request, Reply)>, monitor: Monitor) -> Self {
+        Control { requests, monitor }
+    }
+
+    /// Have the event loop handle `request` and wait for its answer.
+    pub async fn request(&self, request: Request) -> Response {
+        let (tx, rx) = oneshot::channel();
+        let response = match self.requests.send((request, tx)) {
+            Ok(()) => rx
+                .await
+                .map_err(|_| anyhow!("The daemon dropped the request")),
+            Err(_) => Err(anyhow!("The daemon is shutting down")),
+        };
+        response.unwrap_or_else(|e| Response::Error {
+            message: e.to_string(),
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.monitor.subscribe()
+    }
+}
@@ -0,0 +1,663 @@
+//! The running daemon: the applied config, the lines it holds and the event
+//! loop that dispatches presses and picks up config reloads.
+
+use std::{collections::HashMap, fmt::Display, hash::Hash, path::Path, pin::Pin};
+
+use anyhow::anyhow;
+use futures::{
+    future::{self, FutureExt},
+    stream::{Stream, StreamExt},
+};
+use log::{debug, error, info, warn};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    time::{self, Interval},
+};
+
+use crate::{
+    action::Action,
+    config::{
+        log_level_to_enum, pin_number, read_config, sanitise_gpio_names, AppConfig, InputBinding,
+    },
+    control::{Control, Reply, Request, Response},
+    dbus::{self, Service},
+    dispatch::{exec_binding, setup_encoder, setup_input, setup_output, setup_pwm, Context},
+    encoder::{Direction, EncoderEvent, EncoderStream},
+    http,
+    input::{Gesture, GestureEvent, GestureStream},
+    mpd::Mpd,
+    mpris::{self, Mpris, PlaybackStatus, StatusWatcher},
+    mqtt::{self, Bridge},
+    notify, power,
+    socket::{self, Server},
+    validate::validate,
+    watchdog::Watchdog,
+    Void,
+};
+
+pub struct Daemon {
+    /// The config as applied to the hardware, with sanitised pin names.
+    cfg: AppConfig,
+    ctx: Context,
+    inputs: HashMap<u32, GestureStream>,
+    encoders: HashMap<String, EncoderStream>,
+    /// Playback status of the MPRIS player, while outputs follow it.
+    playback: Option<StatusWatcher>,
+    /// Handed out to the control interfaces, which send their requests to
+    /// `requests`.
+    control: Control,
+    requests: mpsc::UnboundedReceiver<(Request, Reply)>,
+    socket: Option<Server>,
+    service: Option<Service>,
+    bridge: Option<Bridge>,
+    http: Option<http::Server>,
+    watchdog: Option<Watchdog>,
+}
+
+impl Daemon {
+    /// Set up every binding of a validated config.
+    pub async fn start(cfg: AppConfig, ctx: Context) -> anyhow::Result<Self> {
+        let (tx, requests) = mpsc::unbounded_channel();
+        let control = Control::new(tx, ctx.monitor.clone());
+        let mut daemon = Daemon {
+            cfg: AppConfig {
+                input_binding: HashMap::new(),
+                output_binding: HashMap::new(),
+                encoder: HashMap::new(),
+                pwm_output: HashMap::new(),
+                ..cfg.clone()
+            },
+            ctx,
+            inputs: HashMap::new(),
+            encoders: HashMap::new(),
+            playback: None,
+            control,
+            requests,
+            socket: None,
+            service: None,
+            bridge: None,
+            http: None,
+            watchdog: None,
+        };
+        daemon.apply(sanitise_gpio_names(cfg)).await?;
+        Ok(daemon)
+    }
+
+    pub fn config(&self) -> &AppConfig {
+        &self.cfg
+    }
+
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// Run the event loop until SIGTERM or SIGINT, then shut down cleanly.
+    /// SIGHUP reloads the config from `config_path`.
+    ///
+    /// Tells systemd the daemon is ready and keeps its watchdog and the
+    /// hardware watchdog fed.
+    pub async fn tick(&mut self, config_path: &Path) -> Void {
+        debug!("Event loop started");
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut pings = notify::watchdog_interval().map(|period| {
+            debug!("Pinging the systemd watchdog every {:?}", period);
+            time::interval(period)
+        });
+        notify::notify(&format!("READY=1\n{}", notify::status(&self.cfg)));
+
+        loop {
+            tokio::select! {
+                (_, info) = next_event(&mut self.inputs) => self.on_press(info).await,
+                (name, event) = next_event(&mut self.encoders) => {
+                    self.on_encoder(&name, event).await;
+                }
+                Some((request, reply)) = self.requests.recv() => {
+                    let response = self.handle(request).await;
+                    // The client may have given up waiting.
+                    let _ = reply.send(response);
+                }
+                status = next_status(&mut self.playback) => self.show_status(status),
+                _ = hangup.recv() => self.reload(config_path).await,
+                _ = next_ping(&mut pings) => notify::notify("WATCHDOG=1"),
+                fed = next_feed(&mut self.watchdog) => {
+                    if let Err(e) = fed {
+                        error!("{:#}", e);
+                    }
+                }
+                _ = terminate.recv() => break,
+                _ = interrupt.recv() => break,
+            }
+        }
+
+        self.shutdown();
+        Ok(())
+    }
+
+    /// Run the action bound to a gesture on an input line.
+    async fn on_press(&mut self, info: GestureEvent) {
+        debug!("GPIO {} reported {:?}", info.line, info.gesture);
+        let binding = self.cfg.input_binding.get(&info.line.to_string());
+        let action = binding.and_then(|b| b.action_for(info.gesture));
+        let result = match action {
+            Some(action) => {
+                debug!("Execute {}", info.line);
+                exec_binding(action, &mut self.ctx, info.line, Some(&info)).await
+            }
+            None => Ok(()),
+        };
+        if let (Some(action), Err(e)) = (action, &result) {
+            error!("GPIO {}: {:?} failed: {:#}", info.line, action, e);
+        }
+        self.ctx.monitor.press(&info, action, &result);
+    }
+
+    async fn handle(&mut self, request: Request) -> Response {
+        match request {
+            // Passed on to the socket and the HTTP server, not for everyone.
+            Request::Bindings => Response::Bindings {
+                config: Box::new(self.cfg.redacted()),
+            },
+            Request::Levels => Response::Levels {
+                inputs: self.ctx.monitor.input_levels(),
+                outputs: self
+                    .ctx
+                    .outputs
+                    .iter()
+                    .filter_map(|(line, output)| Some((*line, output.value().ok()?)))
+                    .collect(),
+                pwm: self
+                    .ctx
+                    .pwm
+                    .iter()
+                    .map(|(name, pwm)| (name.clone(), pwm.duty()))
+                    .collect(),
+            },
+            Request::Trigger {
+                input,
+                gesture,
+                turn,
+            } => self.trigger(&input, gesture, turn).await.into(),
+            Request::Set { output, value } => {
+                let action = match value {
+                    true => Action::Set { output },
+                    false => Action::Clear { output },
+                };
+                exec_binding(&action, &mut self.ctx, output, None)
+                    .await
+                    .into()
+            }
+            Request::Subscribe => Response::Error {
+                message: "Subscriptions are handled by the transport".to_string(),
+            },
+        }
+    }
+
+    /// Run the action bound to `gesture` on input `key` as if it was pressed,
+    /// or to a turn or press of the encoder `key` names.
+    async fn trigger(&mut self, key: &str, gesture: Gesture, turn: Option<Direction>) -> Void {
+        let line = pin_number(key).ok();
+        let input = match (line, turn) {
+            (Some(line), None) => self
+                .cfg
+                .input_binding
+                .get(&line.to_string())
+                .zip(Some(line)),
+            _ => None,
+        };
+        if let Some((binding, line)) = input {
+            let action = binding
+                .action_for(gesture)
+                .ok_or_else(|| anyhow!("GPIO {} has no {:?} press bound", line, gesture))?;
+            info!("Trigger {:?} on GPIO {}", action, line);
+            return exec_binding(action, &mut self.ctx, line, None).await;
+        }
+
+        let (name, binding) = self
+            .cfg
+            .encoder
+            .iter()
+            .find(|(name, binding)| {
+                *name == key
+                    || line.is_some_and(|line| {
+                        [binding.pin_a, binding.pin_b].contains(&line)
+                            || binding.switch_pin == Some(line)
+                    })
+            })
+            .ok_or_else(|| match turn {
+                Some(_) => anyhow!("{} is not an encoder", key),
+                None => anyhow!("{} is not an input binding or encoder", key),
+            })?;
+        let (action, line) = match turn {
+            Some(Direction::Clockwise) => (&binding.clockwise, binding.pin_a),
+            Some(Direction::CounterClockwise) => (&binding.counter_clockwise, binding.pin_a),
+            None => match (&binding.press, binding.switch_pin) {
+                (Some(action), Some(line)) if gesture == Gesture::Short => (action, line),
+                _ => anyhow::bail!("Encoder {} has no {:?} press bound", name, gesture),
+            },
+        };
+        info!("Trigger {:?} on encoder {}", action, name);
+        exec_binding(action, &mut self.ctx, line, None).await
+    }
+
+    /// Run the action bound to a turn or press of encoder `name`, once per step.
+    async fn on_encoder(&mut self, name: &str, event: EncoderEvent) {
+        let binding = match self.cfg.encoder.get(name) {
+            Some(binding) => binding,
+            None => return,
+        };
+        let (action, line, press, steps) = match &event {
+            EncoderEvent::Turn(turn) => {
+                debug!(
+                    "Encoder {} turned {:?} x{}",
+                    name, turn.direction, turn.steps
+                );
+                let action = match turn.direction {
+                    Direction::Clockwise => &binding.clockwise,
+                    Direction::CounterClockwise => &binding.counter_clockwise,
+                };
+                (action, binding.pin_a, None, turn.steps)
+            }
+            EncoderEvent::Press(press) => match &binding.press {
+                Some(action) => (action, press.line, Some(press), 1),
+                None => return,
+            },
+        };
+        for _ in 0..steps {
+            if let Err(e) = exec_binding(action, &mut self.ctx, line, press).await {
+                error!("Encoder {}: {:?} failed: {:#}", name, action, e);
+                break;
+            }
+        }
+    }
+
+    /// Switch the `mpris.status_outputs` lines to match `status`.
+    fn show_status(&self, status: PlaybackStatus) {
+        for (key, wanted) in &self.cfg.mpris.status_outputs {
+            let output = pin_number(key)
+                .ok()
+                .and_then(|line| self.ctx.outputs.get(&line));
+            if let Some(output) = output {
+                if let Err(e) = output.set_value(status == *wanted) {
+                    error!("Failed to set GPIO {}: {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// Drive every output to its `on_exit` level and release all lines.
+    pub fn shutdown(&mut self) {
+        info!("Shutting down");
+        notify::notify("STOPPING=1");
+        self.socket = None;
+        self.service = None;
+        self.bridge = None;
+        self.http = None;
+        self.ctx.effects.clear();
+        for (gpio, binding) in &self.cfg.output_binding {
+            let level = match binding.on_exit {
+                Some(level) => level,
+                None => continue,
+            };
+            let output = gpio
+                .parse::<u32>()
+                .ok()
+                .and_then(|line| self.ctx.outputs.get(&line));
+            if let Some(output) = output {
+                debug!("Set GPIO {} to {} on exit", gpio, level);
+                if let Err(e) = output.set_value(level) {
+                    error!("Failed to set GPIO {} on exit: {}", gpio, e);
+                }
+            }
+        }
+        self.ctx.fades.clear();
+        for (name, binding) in &self.cfg.pwm_output {
+            let (duty, pwm) = match (binding.on_exit, self.ctx.pwm.get(name)) {
+                (Some(duty), Some(pwm)) => (duty, pwm),
+                _ => continue,
+            };
+            debug!("Set PWM {} to {}% on exit", name, duty);
+            if let Err(e) = pwm.set(None, duty) {
+                error!("Failed to set PWM {} on exit: {}", name, e);
+            }
+        }
+        self.inputs.clear();
+        self.encoders.clear();
+        self.ctx.outputs.clear();
+        self.ctx.pwm.clear();
+        // Last, so a shutdown that hangs still gets the machine rebooted.
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.close();
+        }
+        info!("Released all lines, clean shutdown");
+    }
+
+    /// Re-read the config and apply what changed. An unreadable or invalid
+    /// file leaves the running config untouched.
+    pub async fn reload(&mut self, config_path: &Path) {
+        info!("Reloading {}", config_path.display());
+        notify::notify("RELOADING=1");
+        self.load(config_path).await;
+        notify::notify(&format!("READY=1\n{}", notify::status(&self.cfg)));
+    }
+
+    async fn load(&mut self, config_path: &Path) {
+        let (cfg, document) = match read_config(config_path) {
+            Ok(read) => read,
+            Err(e) => {
+                error!("Keeping the current config: {:#}", e);
+                self.ctx.metrics.reload("rejected");
+                return;
+            }
+        };
+        let problems = validate(&cfg, &document, Some(self.ctx.gpio.num_lines()));
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("{}", problem);
+            }
+            error!(
+                "Keeping the current config, found {} problem(s)",
+                problems.len()
+            );
+            self.ctx.metrics.reload("rejected");
+            return;
+        }
+        match self.apply(sanitise_gpio_names(cfg)).await {
+            Ok(()) => {
+                info!("Reloaded {}", config_path.display());
+                self.ctx.metrics.reload("ok");
+            }
+            Err(e) => {
+                error!("Reload incomplete: {:#}", e);
+                self.ctx.metrics.reload("incomplete");
+            }
+        }
+    }
+
+    /// Move the hardware from the applied config to `new`.
+    ///
+    /// Lines whose binding didn't change are left alone, and an output whose
+    /// line settings are unchanged is driven through the handle it already
+    /// has, so it never glitches. Stale lines are released before anything
+    /// is requested, which lets a pin move between inputs and outputs.
+    async fn apply(&mut self, new: AppConfig) -> Void {
+        if new.master_chip != self.cfg.master_chip {
+            warn!(
+                "Switching to {} needs a restart, staying on {}",
+                new.master_chip, self.cfg.master_chip
+            );
+        }
+        if new.log_level != self.cfg.log_level {
+            log::set_max_level(log_level_to_enum(new.log_level));
+        }
+        if new.power_backend != self.cfg.power_backend {
+            info!("Power backend {:?}", new.power_backend);
+            self.ctx.power = power::controller(new.power_backend);
+        }
+        if &new.mpd != self.ctx.mpd.config() {
+            self.ctx.mpd = Mpd::spawn(new.mpd.clone());
+        }
+        if &new.mpris != self.ctx.mpris.config() {
+            self.ctx.mpris = Mpris::spawn(new.mpris.clone());
+            self.playback = None;
+            if !new.mpris.status_outputs.is_empty() {
+                self.playback = Some(mpris::watch_status(new.mpris.clone()));
+            }
+        }
+
+        if new.control != self.cfg.control || (self.socket.is_none() && new.control.enabled) {
+            self.socket = None;
+            if new.control.enabled {
+                self.socket = socket::listen(&new.control, self.control())
+                    .map_err(|e| warn!("No control socket: {:#}", e))
+                    .ok();
+            }
+        }
+        if new.dbus != self.cfg.dbus || (self.service.is_none() && new.dbus.enabled) {
+            self.service = None;
+            if new.dbus.enabled {
+                self.service = dbus::publish(&new.dbus, self.control())
+                    .await
+                    .map_err(|e| warn!("No D-Bus service: {:#}", e))
+                    .ok();
+            }
+        }
+        if new.http != self.cfg.http || (self.http.is_none() && new.http.enabled) {
+            self.http = None;
+            if new.http.enabled {
+                self.http = http::serve(&new.http, self.control(), self.ctx.metrics.clone())
+                    .map_err(|e| warn!("No HTTP server: {:#}", e))
+                    .ok();
+            }
+        }
+        if new.watchdog != self.cfg.watchdog || (self.watchdog.is_none() && new.watchdog.enabled) {
+            // Closed first, most devices can be opened only once.
+            if let Some(watchdog) = self.watchdog.take() {
+                watchdog.close();
+            }
+            if new.watchdog.enabled {
+                self.watchdog = Watchdog::open(&new.watchdog)
+                    .map_err(|e| warn!("No watchdog: {:#}", e))
+                    .ok();
+            }
+        }
+
+        let stale_inputs: Vec<String> = self
+            .cfg
+            .input_binding
+            .iter()
+            .filter(|(key, binding)| match new.input_binding.get(*key) {
+                Some(next) => {
+                    !same_input(binding, self.cfg.debounce_ms, next, new.debounce_ms)
+                        || !key
+                            .parse()
+                            .is_ok_and(|line| self.inputs.contains_key(&line))
+                }
+                None => true,
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale_inputs {
+            info!("Release input GPIO {}", key);
+            self.cfg.input_binding.remove(&key);
+            let line = key.parse::<u32>()?;
+            self.inputs.remove(&line);
+            self.ctx.monitor.forget(line);
+        }
+
+        let stale_outputs: Vec<String> = self
+            .cfg
+            .output_binding
+            .iter()
+            .filter(|(key, binding)| match new.output_binding.get(*key) {
+                Some(next) => next.line_config() != binding.line_config(),
+                None => true,
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale_outputs {
+            info!("Release output GPIO {}", key);
+            self.cfg.output_binding.remove(&key);
+            let line = key.parse::<u32>()?;
+            self.ctx.effects.remove(&line);
+            self.ctx.outputs.remove(&line);
+        }
+
+        let stale_encoders: Vec<String> = self
+            .cfg
+            .encoder
+            .iter()
+            .filter(|(name, binding)| {
+                new.encoder.get(*name) != Some(binding) || !self.encoders.contains_key(*name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in stale_encoders {
+            info!("Release encoder {}", name);
+            self.cfg.encoder.remove(&name);
+            self.encoders.remove(&name);
+        }
+
+        let stale_pwm: Vec<String> = self
+            .cfg
+            .pwm_output
+            .iter()
+            .filter(|(name, binding)| match new.pwm_output.get(*name) {
+                Some(next) if new.pwm_root == self.cfg.pwm_root => {
+                    (next.chip, next.channel) != (binding.chip, binding.channel)
+                }
+                _ => true,
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in stale_pwm {
+            self.cfg.pwm_output.remove(&name);
+            self.ctx.fades.remove(&name);
+            if let Some(pwm) = self.ctx.pwm.remove(&name) {
+                if let Err(e) = pwm.release() {
+                    warn!("Failed to release PWM {}: {:#}", name, e);
+                }
+            }
+        }
+
+        info!("Setup outputs");
+        for (gpio, binding) in &new.output_binding {
+            // A held line only goes back to its startup level when that
+            // changed, not for a new `on_exit`, say.
+            let current = self.cfg.output_binding.get(gpio);
+            if current.map(|current| &current.action) != Some(&binding.action) {
+                setup_output(&mut self.ctx, gpio.parse::<u32>()?, binding).await?;
+            }
+            self.cfg
+                .output_binding
+                .insert(gpio.clone(), binding.clone());
+        }
+
+        info!("Setup input");
+        for (gpio, binding) in &new.input_binding {
+            if let Some(current) = self.cfg.input_binding.get_mut(gpio) {
+                *current = binding.clone();
+                continue;
+            }
+            let line = gpio.parse::<u32>()?;
+            let events = setup_input(&mut self.ctx, line, binding, new.debounce_ms)?;
+            self.inputs.insert(line, events);
+            self.cfg.input_binding.insert(gpio.clone(), binding.clone());
+        }
+
+        for (name, binding) in &new.encoder {
+            if self.cfg.encoder.contains_key(name) {
+                continue;
+            }
+            info!("Setup encoder {}", name);
+            self.encoders
+                .insert(name.clone(), setup_encoder(&mut self.ctx, binding)?);
+            self.cfg.encoder.insert(name.clone(), binding.clone());
+        }
+
+        for (name, binding) in &new.pwm_output {
+            if self.cfg.pwm_output.get(name) == Some(binding) {
+                continue;
+            }
+            setup_pwm(&mut self.ctx, &new.pwm_root, name, binding).await?;
+            self.cfg.pwm_output.insert(name.clone(), binding.clone());
+        }
+
+        let mqtt_changed = new.mqtt != self.cfg.mqtt;
+        self.cfg = AppConfig {
+            input_binding: std::mem::take(&mut self.cfg.input_binding),
+            output_binding: std::mem::take(&mut self.cfg.output_binding),
+            encoder: std::mem::take(&mut self.cfg.encoder),
+            pwm_output: std::mem::take(&mut self.cfg.pwm_output),
+            ..new
+        };
+
+        // The bridge announces the bindings, so it follows them once they
+        // are applied.
+        match &self.bridge {
+            Some(bridge) if !mqtt_changed => bridge.update(&self.cfg),
+            _ => {
+                self.bridge = None;
+                if self.cfg.mqtt.enabled {
+                    self.bridge = Some(mqtt::connect(&self.cfg, self.control()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether an input can keep its line and event stream across a reload.
+fn same_input(
+    old: &InputBinding,
+    old_debounce: u64,
+    new: &InputBinding,
+    new_debounce: u64,
+) -> bool {
+    old == new && old.debounce_ms.unwrap_or(old_debounce) == new.debounce_ms.unwrap_or(new_debounce)
+}
+
+/// Wait for the next watchdog ping, forever without a watchdog.
+async fn next_ping(pings: &mut Option<Interval>) {
+    match pings {
+        Some(pings) => {
+            pings.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Feed the watchdog when it is due, never without one.
+async fn next_feed(watchdog: &mut Option<Watchdog>) -> Void {
+    match watchdog {
+        Some(watchdog) => watchdog.feed().await,
+        None => future::pending().await,
+    }
+}
+
+/// Wait for the next playback status, forever if nothing follows it.
+async fn next_status(playback: &mut Option<StatusWatcher>) -> PlaybackStatus {
+    if let Some(status) = playback.as_mut() {
+        if let Some(status) = status.recv().await {
+            return status;
+        }
+        warn!("MPRIS status watcher is gone");
+        *playback = None;
+    }
+    future::pending().await
+}
+
+type EventStream<T> = Pin<Box<dyn Stream<Item = anyhow::Result<T>> + Send>>;
+
+/// Wait for the next event on any of `streams` and tell which one it came
+/// from. Streams that end or fail are dropped until a reload sets them up
+/// again; with none left this never resolves.
+async fn next_event<K, T>(streams: &mut HashMap<K, EventStream<T>>) -> (K, T)
+where
+    K: Clone + Eq + Hash + Display,
+{
+    loop {
+        if streams.is_empty() {
+            return future::pending().await;
+        }
+        let ((key, event), _, _) = future::select_all(
+            streams
+                .iter_mut()
+                .map(|(key, events)| events.next().map(move |event| (key.clone(), event))),
+        )
+        .await;
+        match event {
+            Some(Ok(event)) => return (key, event),
+            Some(Err(e)) => {
+                error!("{} event stream failed: {:#}", key, e);
+                streams.remove(&key);
+            }
+            None => {
+                warn!("{} event stream closed", key);
+                streams.remove(&key);
+            }
+        }
+    }
+}
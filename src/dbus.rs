@@ -0,0 +1,371 @@
+//! The `io.github.radio.Manager` D-Bus service, so desktop and player
+//! software can react to the buttons and drive the outputs.
+//!
+//! Object `/io/github/radio/Manager`, interface `io.github.radio.Manager`:
+//!
+//! * `Trigger(s input, s gesture)` runs the action bound to an input.
+//! * `SetOutput(u output, b value)` drives an output binding.
+//! * `InputLevels a{ub}`, `OutputLevels a{ub}` and `PwmDuty a{sy}` are the
+//!   current line states, read on demand.
+//! * `ButtonPressed(u pin, s gesture)` is emitted for every recognised press.
+//!
+//! zbus is blocking, so the object server gets a thread of its own and
+//! signals are sent from the blocking thread pool.
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Handle, sync::broadcast::error::RecvError, task, time};
+use zbus::{dbus_interface, fdo, Connection, Message, ObjectServer};
+
+use crate::{
+    control::{Control, Event, Request, Response},
+    effect::Effect,
+    input::Gesture,
+    mpris::Bus,
+    Void,
+};
+
+pub const DEFAULT_NAME: &str = "io.github.radio.Manager";
+const PATH: &str = "/io/github/radio/Manager";
+const INTERFACE: &str = "io.github.radio.Manager";
+/// How long connecting and taking the name may hold up a (re)load.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ```toml
+/// [dbus]
+/// enabled = true
+/// bus = "system"
+/// ```
+///
+/// Owning a name on the system bus needs a policy, see
+/// `contrib/io.github.radio.Manager.conf`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DbusConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "system_bus")]
+    pub bus: Bus,
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+impl Default for DbusConfig {
+    fn default() -> Self {
+        DbusConfig {
+            enabled: false,
+            bus: system_bus(),
+            name: default_name(),
+        }
+    }
+}
+
+fn system_bus() -> Bus {
+    Bus::System
+}
+
+fn default_name() -> String {
+    DEFAULT_NAME.to_string()
+}
+
+/// The published service. Dropping it stops sending signals and wakes the
+/// object server thread, which then releases the bus name.
+pub struct Service {
+    conn: Connection,
+    stopped: Arc<AtomicBool>,
+    _signals: Effect,
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Err(e) = self.wake_server() {
+            debug!("Failed to wake the D-Bus object server: {:#}", e);
+        }
+    }
+}
+
+impl Service {
+    /// Ping the connection's own unique name, so the object server thread
+    /// gets a message and sees it was stopped. Nothing waits for the reply.
+    fn wake_server(&self) -> Void {
+        let ping = Message::method(
+            None,
+            self.conn.unique_name(),
+            PATH,
+            Some("org.freedesktop.DBus.Peer"),
+            "Ping",
+            &(),
+        )?;
+        self.conn.send_message(ping)?;
+        Ok(())
+    }
+}
+
+/// Own the configured name and serve `control` under it.
+pub async fn publish(cfg: &DbusConfig, control: Control) -> anyhow::Result<Service> {
+    let connect = {
+        let cfg = cfg.clone();
+        task::spawn_blocking(move || own_name(&cfg))
+    };
+    // A connection that comes through after the timeout is dropped by its
+    // thread, and the name with it.
+    let conn = time::timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "The {:?} bus did not answer in {:?}",
+                cfg.bus,
+                CONNECT_TIMEOUT
+            )
+        })???;
+    info!("D-Bus service {} on the {:?} bus", cfg.name, cfg.bus);
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let manager = Manager {
+        control: control.clone(),
+        runtime: Handle::current(),
+    };
+    let server_conn = conn.clone();
+    let server_stopped = stopped.clone();
+    let name = cfg.name.clone();
+    thread::spawn(move || {
+        if let Err(e) = serve(&server_conn, manager, &server_stopped) {
+            warn!("D-Bus service: {:#}", e);
+        }
+        if let Err(e) = release(&server_conn, &name) {
+            debug!("Failed to release {}: {:#}", name, e);
+        }
+        debug!("D-Bus object server stopped");
+    });
+
+    let signals = Effect::spawn(forward_presses(conn.clone(), control));
+    Ok(Service {
+        conn,
+        stopped,
+        _signals: signals,
+    })
+}
+
+fn own_name(cfg: &DbusConfig) -> anyhow::Result<Connection> {
+    let conn = cfg.bus.connect()?;
+    let reply = fdo::DBusProxy::new(&conn)?
+        .request_name(&cfg.name, fdo::RequestNameFlags::DoNotQueue.into())?;
+    if reply != fdo::RequestNameReply::PrimaryOwner {
+        bail!("{} is owned by another process", cfg.name);
+    }
+    Ok(conn)
+}
+
+fn serve(conn: &Connection, manager: Manager, stopped: &AtomicBool) -> Void {
+    let mut server = ObjectServer::new(conn);
+    server.at(&PATH.try_into()?, manager)?;
+    while !stopped.load(Ordering::Relaxed) {
+        match server.try_handle_next() {
+            Ok(_) => {}
+            // The connection is gone, and the name with it.
+            Err(zbus::Error::Io(e)) => bail!("Lost the bus connection: {}", e),
+            Err(e) => warn!("D-Bus service: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn release(conn: &Connection, name: &str) -> Void {
+    fdo::DBusProxy::new(conn)?.release_name(name)?;
+    Ok(())
+}
+
+/// Emit `ButtonPressed` for every press the daemon reports.
+async fn forward_presses(conn: Connection, control: Control) {
+    let mut events = control.subscribe();
+    loop {
+        let (pin, gesture) = match events.recv().await {
+            Ok(Event::Press { pin, gesture, .. }) => (pin, gesture),
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("D-Bus service missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if let Err(e) = button_pressed(conn.clone(), pin, gesture).await {
+            warn!("Failed to emit ButtonPressed: {:#}", e);
+        }
+    }
+}
+
+async fn button_pressed(conn: Connection, pin: u32, gesture: Gesture) -> Void {
+    task::spawn_blocking(move || {
+        conn.emit_signal(
+            None,
+            PATH,
+            INTERFACE,
+            "ButtonPressed",
+            &(pin, gesture.name()),
+        )
+    })
+    .await??;
+    Ok(())
+}
+
+struct Manager {
+    control: Control,
+    /// The object server thread is outside the runtime and waits on it for
+    /// the daemon's answers.
+    runtime: Handle,
+}
+
+impl Manager {
+    fn request(&self, request: Request) -> Response {
+        self.runtime.block_on(self.control.request(request))
+    }
+
+    fn run(&self, request: Request) -> fdo::Result<()> {
+        match self.request(request) {
+            Response::Error { message } => Err(fdo::Error::Failed(message)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The line states, empty if the daemon didn't answer.
+    fn levels(&self) -> (HashMap<u32, bool>, HashMap<u32, bool>, HashMap<String, u8>) {
+        match self.request(Request::Levels) {
+            Response::Levels {
+                inputs,
+                outputs,
+                pwm,
+            } => (
+                inputs.into_iter().collect(),
+                outputs.into_iter().collect(),
+                pwm.into_iter().collect(),
+            ),
+            _ => Default::default(),
+        }
+    }
+}
+
+#[dbus_interface(name = "io.github.radio.Manager")]
+impl Manager {
+    /// Run the action bound to a gesture of an input, as if it was pressed.
+    fn trigger(&self, input: &str, gesture: &str) -> fdo::Result<()> {
+        let gesture = gesture
+            .parse()
+            .map_err(|e: anyhow::Error| fdo::Error::InvalidArgs(e.to_string()))?;
+        self.run(Request::Trigger {
+            input: input.to_string(),
+            gesture,
+            turn: None,
+        })
+    }
+
+    /// Drive an output binding.
+    fn set_output(&self, output: u32, value: bool) -> fdo::Result<()> {
+        self.run(Request::Set { output, value })
+    }
+
+    #[dbus_interface(property)]
+    fn input_levels(&self) -> HashMap<u32, bool> {
+        self.levels().0
+    }
+
+    #[dbus_interface(property)]
+    fn output_levels(&self) -> HashMap<u32, bool> {
+        self.levels().1
+    }
+
+    /// Duty cycle of each PWM output in percent.
+    #[dbus_interface(property)]
+    fn pwm_duty(&self) -> HashMap<String, u8> {
+        self.levels().2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tokio::{runtime::Runtime, sync::mpsc};
+
+    use super::*;
+    use crate::control::Monitor;
+
+    #[test]
+    fn methods_are_requests_to_the_daemon() {
+        let runtime = Runtime::new().unwrap();
+        let (requests, mut received) = mpsc::unbounded_channel();
+        let manager = Manager {
+            control: Control::new(requests, Monitor::default()),
+            runtime: runtime.handle().clone(),
+        };
+        // Answers like the event loop, and keeps what it was asked.
+        let daemon = runtime.spawn(async move {
+            let mut seen = Vec::new();
+            while let Some((request, reply)) = received.recv().await {
+                let response = match &request {
+                    Request::Set { output: 5, .. } => Response::Error {
+                        message: "GPIO 5 is not an output binding".to_string(),
+                    },
+                    Request::Levels => Response::Levels {
+                        inputs: BTreeMap::new(),
+                        outputs: vec![(6, true)].into_iter().collect(),
+                        pwm: BTreeMap::new(),
+                    },
+                    _ => Response::Ok,
+                };
+                seen.push(request);
+                let _ = reply.send(response);
+            }
+            seen
+        });
+
+        manager.trigger("gpio17", "long").unwrap();
+        assert!(matches!(
+            manager.trigger("gpio17", "triple"),
+            Err(fdo::Error::InvalidArgs(_))
+        ));
+        manager.set_output(6, true).unwrap();
+        match manager.set_output(5, true) {
+            Err(fdo::Error::Failed(message)) => {
+                assert_eq!(message, "GPIO 5 is not an output binding")
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            manager.output_levels(),
+            vec![(6, true)].into_iter().collect()
+        );
+
+        drop(manager);
+        assert_eq!(
+            runtime.block_on(daemon).unwrap(),
+            vec![
+                Request::Trigger {
+                    input: "gpio17".to_string(),
+                    gesture: Gesture::Long,
+                    turn: None,
+                },
+                Request::Set {
+                    output: 6,
+                    value: true,
+                },
+                Request::Set {
+                    output: 5,
+                    value: true,
+                },
+                Request::Levels,
+            ]
+        );
+    }
+}
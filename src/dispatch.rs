@@ -0,0 +1,296 @@
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use futures::stream::StreamExt;
+use log::{debug, info};
+
+use crate::{
+    action::Action,
+    command::run_command,
+    config::{EncoderBinding, InputBinding, OutputBinding, PwmBinding},
+    control::Monitor,
+    effect::{self, Effect, Pattern},
+    encoder::{self, Decoder, EncoderStream},
+    gpio::{EdgeStream, GpioBackend, LineConfig, OutputLine},
+    input::{self, GestureConfig, GestureEvent, GestureStream},
+    metrics::Metrics,
+    mpd::{Mpd, MpdCommand, MpdConfig},
+    mpris::{Mpris, MprisCommand, MprisConfig},
+    power::{PowerAction, PowerController},
+    pwm::{self, PwmChannel},
+    Void,
+};
+
+/// Everything a binding may act on.
+pub struct Context {
+    pub gpio: Box<dyn GpioBackend>,
+    pub power: Box<dyn PowerController>,
+    /// Requested output lines. Removing one releases the line.
+    pub outputs: HashMap<u32, Arc<dyn OutputLine>>,
+    /// Patterns running on output lines. Removing one stops it.
+    pub effects: HashMap<u32, Effect>,
+    /// Exported PWM channels by name.
+    pub pwm: HashMap<String, Arc<PwmChannel>>,
+    /// Fades running on PWM channels. Removing one stops it.
+    pub fades: HashMap<String, Effect>,
+    /// Runs on a task of its own, MPD actions only queue their commands.
+    pub mpd: Mpd,
+    /// Like `mpd`, on a task of its own.
+    pub mpris: Mpris,
+    pub monitor: Monitor,
+    pub metrics: Metrics,
+}
+
+impl Context {
+    pub fn new(gpio: Box<dyn GpioBackend>, power: Box<dyn PowerController>) -> Self {
+        Context {
+            gpio,
+            power,
+            outputs: HashMap::new(),
+            effects: HashMap::new(),
+            pwm: HashMap::new(),
+            fades: HashMap::new(),
+            mpd: Mpd::spawn(MpdConfig::default()),
+            mpris: Mpris::spawn(MprisConfig::default()),
+            monitor: Monitor::default(),
+            metrics: Metrics::default(),
+        }
+    }
+}
+
+/// Run `action` for `line`. `event` is the press that fired it, `None` for
+/// actions run at startup.
+pub async fn exec_binding(
+    action: &Action,
+    ctx: &mut Context,
+    line: u32,
+    event: Option<&GestureEvent>,
+) -> Void {
+    let result = run_action(action, ctx, line, event).await;
+    ctx.metrics.action(action, event, &result);
+    result
+}
+
+async fn run_action(
+    action: &Action,
+    ctx: &mut Context,
+    line: u32,
+    event: Option<&GestureEvent>,
+) -> Void {
+    match action {
+        Action::None => {}
+        Action::PowerOff => ctx.power.execute(PowerAction::PowerOff)?,
+        Action::Restart => ctx.power.execute(PowerAction::Reboot)?,
+        Action::Halt => ctx.power.execute(PowerAction::Halt)?,
+        Action::SetOn => set_output(ctx, line, true, LineConfig::default())?,
+        Action::SetOff => set_output(ctx, line, false, LineConfig::default())?,
+        Action::Set { output } => drive(ctx, *output, true)?,
+        Action::Clear { output } => drive(ctx, *output, false)?,
+        Action::Toggle { output } => {
+            let level = held_output(ctx, *output)?.value()?;
+            drive(ctx, *output, !level)?
+        }
+        Action::Pulse {
+            output,
+            duration_ms,
+        } => start_effect(
+            ctx,
+            *output,
+            Pattern::Flash {
+                count: 1,
+                on_ms: *duration_ms,
+                off_ms: 0,
+            },
+        )?,
+        Action::Effect { output, pattern } => start_effect(ctx, output.unwrap_or(line), *pattern)?,
+        Action::PwmSet {
+            output,
+            duty,
+            period_ns,
+        } => {
+            ctx.fades.remove(output);
+            pwm_channel(ctx, output)?.set(*period_ns, *duty)?
+        }
+        Action::PwmFade {
+            output,
+            duty,
+            duration_ms,
+        } => {
+            let fade = pwm::fade(
+                output,
+                pwm_channel(ctx, output)?,
+                *duty,
+                Duration::from_millis(*duration_ms),
+            );
+            ctx.fades.insert(output.clone(), fade);
+        }
+        Action::Command(spec) => run_command(spec, line, event)?,
+        Action::MpdToggle => ctx.mpd.send(MpdCommand::Toggle)?,
+        Action::MpdStop => ctx.mpd.send(MpdCommand::Stop)?,
+        Action::MpdNext => ctx.mpd.send(MpdCommand::Next)?,
+        Action::MpdPrevious => ctx.mpd.send(MpdCommand::Previous)?,
+        Action::MpdVolumeUp { step } => ctx.mpd.send(MpdCommand::ChangeVolume(i32::from(*step)))?,
+        Action::MpdVolumeDown { step } => {
+            ctx.mpd.send(MpdCommand::ChangeVolume(-i32::from(*step)))?
+        }
+        Action::MpdPlaylist { name } => ctx.mpd.send(MpdCommand::LoadPlaylist(name.clone()))?,
+        Action::MprisPlayPause => ctx.mpris.send(MprisCommand::PlayPause)?,
+        Action::MprisNext => ctx.mpris.send(MprisCommand::Next)?,
+        Action::MprisPrevious => ctx.mpris.send(MprisCommand::Previous)?,
+        Action::MprisSeek { offset_ms } => ctx.mpris.send(MprisCommand::Seek(*offset_ms))?,
+        Action::MprisVolume { volume } => ctx.mpris.send(MprisCommand::Volume(*volume))?,
+    }
+    Ok(())
+}
+
+/// Apply an output binding.
+pub async fn setup_output(ctx: &mut Context, line: u32, binding: &OutputBinding) -> Void {
+    match binding.action {
+        Action::SetOn => set_output(ctx, line, true, binding.line_config()),
+        Action::SetOff => set_output(ctx, line, false, binding.line_config()),
+        Action::Effect { .. } => {
+            if !ctx.outputs.contains_key(&line) {
+                static_line(ctx, line, false, binding.line_config())?;
+            }
+            exec_binding(&binding.action, ctx, line, None).await
+        }
+        _ => exec_binding(&binding.action, ctx, line, None).await,
+    }
+}
+
+/// Drive an output, requesting the line with `config` if it isn't held yet.
+/// A pattern running on the line is stopped.
+fn set_output(ctx: &mut Context, line: u32, state: bool, config: LineConfig) -> Void {
+    ctx.effects.remove(&line);
+    if let Some(output) = ctx.outputs.get(&line) {
+        debug!("Set GPIO {} to {}", line, state);
+        return output.set_value(state);
+    }
+    static_line(ctx, line, state, config)
+}
+
+fn static_line(ctx: &mut Context, gpionum: u32, state: bool, config: LineConfig) -> Void {
+    info!("Setup GPIO {} output with default {}", gpionum, &state);
+    let output =
+        ctx.gpio
+            .request_output(gpionum, state, config, &format!("static_gpio_{}", gpionum))?;
+    ctx.outputs.insert(gpionum, Arc::from(output));
+    Ok(())
+}
+
+fn held_output(ctx: &Context, line: u32) -> anyhow::Result<&Arc<dyn OutputLine>> {
+    ctx.outputs
+        .get(&line)
+        .ok_or_else(|| anyhow!("GPIO {} is not an output", line))
+}
+
+/// Drive a held output line. Unlike `seton`, this never requests a line.
+fn drive(ctx: &mut Context, line: u32, state: bool) -> Void {
+    held_output(ctx, line)?;
+    set_output(ctx, line, state, LineConfig::default())
+}
+
+/// Replace the pattern running on a held output line.
+fn start_effect(ctx: &mut Context, line: u32, pattern: Pattern) -> Void {
+    ctx.effects.remove(&line);
+    let output = held_output(ctx, line)?;
+    match pattern {
+        Pattern::Off => output.set_value(false)?,
+        _ => {
+            ctx.effects
+                .insert(line, effect::start(line, output, pattern));
+        }
+    }
+    Ok(())
+}
+
+fn pwm_channel<'a>(ctx: &'a Context, name: &str) -> anyhow::Result<&'a Arc<PwmChannel>> {
+    ctx.pwm
+        .get(name)
+        .ok_or_else(|| anyhow!("No PWM output named {}", name))
+}
+
+/// Export and program a PWM channel, or reprogram it if it is held already.
+pub async fn setup_pwm(ctx: &mut Context, root: &Path, name: &str, binding: &PwmBinding) -> Void {
+    ctx.fades.remove(name);
+    if let Some(pwm) = ctx.pwm.get(name) {
+        return pwm.set(Some(binding.period_ns), binding.duty);
+    }
+    info!(
+        "Setup PWM {} on pwmchip{}/pwm{}",
+        name, binding.chip, binding.channel
+    );
+    let pwm = PwmChannel::open(
+        root,
+        binding.chip,
+        binding.channel,
+        binding.period_ns,
+        binding.duty,
+    )
+    .await?;
+    ctx.pwm.insert(name.to_string(), Arc::new(pwm));
+    Ok(())
+}
+
+/// Request edge events for `line`, debounced by the hardware if it can and
+/// in software otherwise. A zero `debounce` leaves the stream unfiltered.
+/// Every edge that passes is reported to the monitor and counted.
+pub fn get_evt_handle(
+    ctx: &mut Context,
+    line: u32,
+    config: LineConfig,
+    debounce: Duration,
+) -> anyhow::Result<EdgeStream> {
+    let gpio = ctx.gpio.as_mut();
+    let mut events = gpio.request_events(line, config, &format!("gpio_event_{}", line))?;
+    if !debounce.is_zero() && !gpio.set_debounce(line, debounce)? {
+        debug!("Software debounce of {:?} on GPIO {}", debounce, line);
+        events = input::debounce(events, debounce);
+    }
+    let monitor = ctx.monitor.clone();
+    let metrics = ctx.metrics.clone();
+    Ok(Box::pin(events.inspect(move |event| {
+        if let Ok(event) = event {
+            monitor.edge(event);
+            metrics.edge(event);
+        }
+    })))
+}
+
+/// Request an input line and recognise the gestures bound on it.
+pub fn setup_input(
+    ctx: &mut Context,
+    line: u32,
+    binding: &InputBinding,
+    default_debounce_ms: u64,
+) -> anyhow::Result<GestureStream> {
+    let debounce = Duration::from_millis(binding.debounce_ms.unwrap_or(default_debounce_ms));
+    let events = get_evt_handle(ctx, line, binding.line_config(), debounce)?;
+    Ok(input::gestures(events, binding.gesture_config()))
+}
+
+/// Request the lines of a rotary encoder and decode its turns.
+pub fn setup_encoder(ctx: &mut Context, binding: &EncoderBinding) -> anyhow::Result<EncoderStream> {
+    let debounce = Duration::from_millis(binding.debounce_ms.unwrap_or(0));
+    let config = binding.line_config();
+    // The decoder has to start from where the knob rests.
+    let mut level = |line: u32| {
+        ctx.gpio
+            .read_input(line, config, &format!("gpio_input_{}", line))
+    };
+    let decoder = Decoder::new(
+        binding.encoder_config(),
+        level(binding.pin_a)?,
+        level(binding.pin_b)?,
+    );
+    let a = get_evt_handle(ctx, binding.pin_a, config, debounce)?;
+    let b = get_evt_handle(ctx, binding.pin_b, config, debounce)?;
+    let switch = match binding.switch_pin {
+        Some(line) => {
+            let events = get_evt_handle(ctx, line, config, debounce)?;
+            Some(input::gestures(events, GestureConfig::default()))
+        }
+        None => None,
+    };
+    Ok(encoder::turns(a, b, switch, decoder))
+}
@@ -0,0 +1,201 @@
+//! Timed patterns on output lines, e.g. a status LED that blinks or breathes.
+//!
+//! Each running pattern is a tokio task next to the event loop. It only
+//! holds a weak reference to its line, so releasing the line on a reload
+//! or at shutdown ends the pattern with it.
+
+use std::{
+    f64::consts::PI,
+    fmt,
+    future::Future,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use crate::{gpio::OutputLine, Void};
+
+/// How long the beats of a heartbeat are on, and the gap between them.
+const BEAT: Duration = Duration::from_millis(100);
+const BEAT_GAP: Duration = Duration::from_millis(150);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "pattern", rename_all = "kebab-case")]
+pub enum Pattern {
+    /// Stop the pattern running on the line and switch it off.
+    Off,
+    Blink {
+        #[serde(default = "default_blink_ms")]
+        on_ms: u64,
+        #[serde(default = "default_blink_ms")]
+        off_ms: u64,
+    },
+    /// Fade in and out with software PWM.
+    Breathe {
+        #[serde(default = "default_breathe_ms")]
+        period_ms: u64,
+        #[serde(default = "default_pwm_hz")]
+        pwm_hz: u32,
+    },
+    /// Two short beats, then a pause until the period is over.
+    Heartbeat {
+        #[serde(default = "default_heartbeat_ms")]
+        period_ms: u64,
+    },
+    /// Flash `count` times, then stay off.
+    Flash {
+        count: u32,
+        #[serde(default = "default_flash_ms")]
+        on_ms: u64,
+        #[serde(default = "default_flash_ms")]
+        off_ms: u64,
+    },
+}
+
+fn default_blink_ms() -> u64 {
+    500
+}
+
+fn default_breathe_ms() -> u64 {
+    3000
+}
+
+fn default_pwm_hz() -> u32 {
+    100
+}
+
+fn default_heartbeat_ms() -> u64 {
+    1200
+}
+
+fn default_flash_ms() -> u64 {
+    100
+}
+
+/// A running pattern. Dropping it stops the pattern.
+pub struct Effect(JoinHandle<()>);
+
+impl Effect {
+    /// Run `task` until it is done or the handle is dropped.
+    pub fn spawn<F>(task: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Effect(tokio::spawn(task))
+    }
+}
+
+impl Drop for Effect {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Run `pattern` on `output` until it is over, the returned handle is
+/// dropped or the line is released.
+pub fn start(line: u32, output: &Arc<dyn OutputLine>, pattern: Pattern) -> Effect {
+    debug!("Run {:?} on GPIO {}", pattern, line);
+    let mut driver = Driver {
+        output: Arc::downgrade(output),
+        at: Instant::now(),
+    };
+    Effect::spawn(async move {
+        match driver.run(pattern).await {
+            Err(e) if e.is::<Released>() => debug!("GPIO {} released, {:?} stopped", line, pattern),
+            Err(e) => warn!("GPIO {}: {:?} failed: {:#}", line, pattern, e),
+            Ok(()) => debug!("GPIO {}: {:?} done", line, pattern),
+        }
+    })
+}
+
+#[derive(Debug)]
+struct Released;
+
+impl fmt::Display for Released {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("line released")
+    }
+}
+
+impl std::error::Error for Released {}
+
+struct Driver {
+    output: Weak<dyn OutputLine>,
+    /// When the current step ends. Steps are chained from here rather than
+    /// from the time they actually started, so the pattern doesn't drift.
+    at: Instant,
+}
+
+impl Driver {
+    async fn run(&mut self, pattern: Pattern) -> Void {
+        match pattern {
+            Pattern::Off => self.set(false),
+            Pattern::Blink { on_ms, off_ms } => loop {
+                self.step(true, ms(on_ms)).await?;
+                self.step(false, ms(off_ms)).await?;
+            },
+            Pattern::Breathe { period_ms, pwm_hz } => {
+                let period = ms(period_ms);
+                let pwm_period = Duration::from_secs(1) / pwm_hz.max(1);
+                let start = self.at;
+                loop {
+                    let phase = (self.at - start).as_secs_f64() / period.as_secs_f64();
+                    let level = (1.0 - (2.0 * PI * phase).cos()) / 2.0;
+                    // Squared, as the eye is more sensitive to changes in dim light.
+                    let on = pwm_period.mul_f64(level * level);
+                    self.step(true, on).await?;
+                    self.step(false, pwm_period - on).await?;
+                }
+            }
+            Pattern::Heartbeat { period_ms } => {
+                let pause = ms(period_ms).saturating_sub(BEAT * 2 + BEAT_GAP);
+                loop {
+                    self.step(true, BEAT).await?;
+                    self.step(false, BEAT_GAP).await?;
+                    self.step(true, BEAT).await?;
+                    self.step(false, pause).await?;
+                }
+            }
+            Pattern::Flash {
+                count,
+                on_ms,
+                off_ms,
+            } => {
+                for _ in 0..count {
+                    self.step(true, ms(on_ms)).await?;
+                    self.step(false, ms(off_ms)).await?;
+                }
+                self.set(false)
+            }
+        }
+    }
+
+    /// Hold `value` for `duration`. Zero length steps are skipped, so a
+    /// PWM duty cycle of 0 or 100% doesn't glitch.
+    async fn step(&mut self, value: bool, duration: Duration) -> Void {
+        if duration.is_zero() {
+            return Ok(());
+        }
+        self.set(value)?;
+        self.at += duration;
+        time::sleep_until(self.at).await;
+        Ok(())
+    }
+
+    fn set(&self, value: bool) -> Void {
+        match self.output.upgrade() {
+            Some(output) => output.set_value(value),
+            None => Err(Released.into()),
+        }
+    }
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
@@ -0,0 +1,350 @@
+//! Quadrature rotary encoders, decoded from the edges of their two lines.
+
+use std::{pin::Pin, str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+use futures::{
+    future,
+    stream::{self, Stream, StreamExt},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gpio::{Edge, EdgeEvent, EdgeStream},
+    input::{GestureEvent, GestureStream},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Clockwise,
+    CounterClockwise,
+}
+
+impl FromStr for Direction {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "clockwise" => Ok(Direction::Clockwise),
+            "counter-clockwise" => Ok(Direction::CounterClockwise),
+            _ => Err(anyhow!(
+                "expected clockwise or counter-clockwise, not {:?}",
+                name
+            )),
+        }
+    }
+}
+
+/// One or more detents in the same direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Turn {
+    pub direction: Direction,
+    /// 1 when turned slowly, more when acceleration kicks in.
+    pub steps: u32,
+    /// Kernel timestamp of the edge that completed the detent, in ns.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderEvent {
+    Turn(Turn),
+    /// The push switch was pressed.
+    Press(GestureEvent),
+}
+
+pub type EncoderStream = Pin<Box<dyn Stream<Item = anyhow::Result<EncoderEvent>> + Send>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderConfig {
+    pub pin_a: u32,
+    pub pin_b: u32,
+    /// Quarter steps between two detents, 4 for most encoders.
+    pub steps_per_detent: u8,
+    /// Detents closer together than this turn faster.
+    pub accel: Option<Duration>,
+    /// Upper bound for the steps a single detent counts as.
+    pub accel_max: u32,
+}
+
+/// Movement between two states of the lines, indexed by `old << 2 | new`
+/// where a state is `a << 1 | b`. Clockwise runs 00, 10, 11, 01, so A leads
+/// B. A jump over a state is a missed edge and doesn't count either way.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Gray code state machine that counts quarter steps into detents.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    cfg: EncoderConfig,
+    /// Logical levels of A and B, `a << 1 | b`.
+    state: u8,
+    /// Quarter steps since the last detent, negative counter-clockwise.
+    count: i8,
+    /// Direction, timestamp and steps of the last detent.
+    last: Option<(Direction, u64, u32)>,
+}
+
+impl Decoder {
+    /// Start from the levels the lines are at. Most encoders rest with both
+    /// contacts open, but some have detents at 11 as well.
+    pub fn new(cfg: EncoderConfig, a: bool, b: bool) -> Self {
+        Decoder {
+            cfg,
+            state: u8::from(a) << 1 | u8::from(b),
+            count: 0,
+            last: None,
+        }
+    }
+
+    pub fn on_edge(&mut self, event: &EdgeEvent) -> Option<Turn> {
+        let bit = if event.line == self.cfg.pin_a {
+            0b10
+        } else {
+            0b01
+        };
+        let new = match event.edge {
+            Edge::Rising => self.state | bit,
+            Edge::Falling => self.state & !bit,
+        };
+        self.count += TRANSITIONS[usize::from(self.state << 2 | new)];
+        self.state = new;
+        if self.count.unsigned_abs() < self.cfg.steps_per_detent.max(1) {
+            return None;
+        }
+
+        let direction = if self.count > 0 {
+            Direction::Clockwise
+        } else {
+            Direction::CounterClockwise
+        };
+        self.count = 0;
+        let steps = match (self.cfg.accel, self.last) {
+            (Some(window), Some((last, at, steps)))
+                if last == direction
+                    && u128::from(event.timestamp.saturating_sub(at)) < window.as_nanos() =>
+            {
+                (steps + 1).min(self.cfg.accel_max.max(1))
+            }
+            _ => 1,
+        };
+        self.last = Some((direction, event.timestamp, steps));
+        Some(Turn {
+            direction,
+            steps,
+            timestamp: event.timestamp,
+        })
+    }
+}
+
+/// Decode the edges of both encoder lines into turns, merged with the
+/// presses of the push switch if there is one.
+pub fn turns(
+    a: EdgeStream,
+    b: EdgeStream,
+    switch: Option<GestureStream>,
+    mut decoder: Decoder,
+) -> EncoderStream {
+    let turns = stream::select(a, b).filter_map(move |event| {
+        future::ready(match event {
+            Ok(event) => decoder
+                .on_edge(&event)
+                .map(|turn| Ok(EncoderEvent::Turn(turn))),
+            Err(e) => Some(Err(e)),
+        })
+    });
+    match switch {
+        Some(switch) => Box::pin(stream::select(
+            turns,
+            switch.map(|event| event.map(EncoderEvent::Press)),
+        )),
+        None => Box::pin(turns),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Direction::{Clockwise as Cw, CounterClockwise as Ccw};
+    use Edge::{Falling as F, Rising as R};
+
+    const A: u32 = 5;
+    const B: u32 = 6;
+    const MS: u64 = 1_000_000;
+
+    /// `(line, edge, ms)`
+    type Edges = Vec<(u32, Edge, u64)>;
+    type Turns = Vec<(Direction, u32)>;
+
+    fn decoder(accel_ms: Option<u64>, rest: (bool, bool)) -> Decoder {
+        let cfg = EncoderConfig {
+            pin_a: A,
+            pin_b: B,
+            steps_per_detent: 4,
+            accel: accel_ms.map(Duration::from_millis),
+            accel_max: 3,
+        };
+        Decoder::new(cfg, rest.0, rest.1)
+    }
+
+    /// Feed the edges and collect the turns as `(direction, steps)`.
+    fn run(decoder: &mut Decoder, edges: &[(u32, Edge, u64)]) -> Turns {
+        edges
+            .iter()
+            .filter_map(|&(line, edge, at_ms)| {
+                decoder.on_edge(&EdgeEvent {
+                    line,
+                    edge,
+                    timestamp: at_ms * MS,
+                })
+            })
+            .map(|turn| (turn.direction, turn.steps))
+            .collect()
+    }
+
+    /// One detent clockwise from 00: 00, 10, 11, 01, 00.
+    fn clockwise(at_ms: u64) -> Edges {
+        vec![(A, R, at_ms), (B, R, at_ms), (A, F, at_ms), (B, F, at_ms)]
+    }
+
+    fn counter_clockwise(at_ms: u64) -> Edges {
+        vec![(B, R, at_ms), (A, R, at_ms), (B, F, at_ms), (A, F, at_ms)]
+    }
+
+    #[test]
+    fn detents() {
+        let table: Vec<(&str, (bool, bool), Edges, Turns)> = vec![
+            ("clockwise", (false, false), clockwise(0), vec![(Cw, 1)]),
+            (
+                "counter-clockwise",
+                (false, false),
+                counter_clockwise(0),
+                vec![(Ccw, 1)],
+            ),
+            (
+                "clockwise from 11",
+                (true, true),
+                vec![(A, F, 0), (B, F, 0), (A, R, 0), (B, R, 0)],
+                vec![(Cw, 1)],
+            ),
+            (
+                "counter-clockwise from 11",
+                (true, true),
+                vec![(B, F, 0), (A, F, 0), (B, R, 0), (A, R, 0)],
+                vec![(Ccw, 1)],
+            ),
+            (
+                "half a detent",
+                (false, false),
+                clockwise(0)[..2].to_vec(),
+                vec![],
+            ),
+            (
+                "there and back",
+                (false, false),
+                vec![(A, R, 0), (B, R, 0), (B, F, 0), (A, F, 0)],
+                vec![],
+            ),
+        ];
+        for (name, rest, edges, turns) in table {
+            assert_eq!(run(&mut decoder(None, rest), &edges), turns, "{}", name);
+        }
+    }
+
+    #[test]
+    fn bounces_and_repeated_edges_cancel_out() {
+        let table: Vec<(&str, Edges)> = vec![
+            (
+                "A bounces",
+                vec![
+                    (A, R, 0),
+                    (A, F, 0),
+                    (A, R, 0),
+                    (B, R, 0),
+                    (A, F, 0),
+                    (B, F, 0),
+                ],
+            ),
+            (
+                "B bounces",
+                vec![
+                    (A, R, 0),
+                    (B, R, 0),
+                    (B, F, 0),
+                    (B, R, 0),
+                    (A, F, 0),
+                    (B, F, 0),
+                ],
+            ),
+            (
+                "repeated edges",
+                vec![
+                    (A, R, 0),
+                    (A, R, 0),
+                    (B, R, 0),
+                    (A, F, 0),
+                    (A, F, 0),
+                    (B, F, 0),
+                ],
+            ),
+        ];
+        for (name, edges) in table {
+            assert_eq!(
+                run(&mut decoder(None, (false, false)), &edges),
+                vec![(Cw, 1)],
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn acceleration() {
+        let table: Vec<(&str, Option<u64>, Vec<u64>, Turns)> = vec![
+            (
+                "off",
+                None,
+                vec![0, 10, 20],
+                vec![(Cw, 1), (Cw, 1), (Cw, 1)],
+            ),
+            (
+                "slow",
+                Some(50),
+                vec![0, 100, 200],
+                vec![(Cw, 1), (Cw, 1), (Cw, 1)],
+            ),
+            (
+                "fast, up to the maximum",
+                Some(50),
+                vec![0, 10, 20, 30],
+                vec![(Cw, 1), (Cw, 2), (Cw, 3), (Cw, 3)],
+            ),
+            (
+                "slowing down starts over",
+                Some(50),
+                vec![0, 10, 100],
+                vec![(Cw, 1), (Cw, 2), (Cw, 1)],
+            ),
+        ];
+        for (name, accel, detents, turns) in table {
+            let edges: Vec<_> = detents.into_iter().flat_map(clockwise).collect();
+            assert_eq!(
+                run(&mut decoder(accel, (false, false)), &edges),
+                turns,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn turning_back_starts_over() {
+        let mut edges = clockwise(0);
+        edges.extend(clockwise(10));
+        edges.extend(counter_clockwise(20));
+        assert_eq!(
+            run(&mut decoder(Some(50), (false, false)), &edges),
+            vec![(Cw, 1), (Cw, 2), (Ccw, 1)]
+        );
+    }
+}
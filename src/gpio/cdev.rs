@@ -0,0 +1,120 @@
+use std::path::Path;
+
+use futures::stream::StreamExt;
+use gpio_cdev::{
+    AsyncLineEventHandle, Chip, EventRequestFlags, EventType, LineHandle, LineRequestFlags,
+};
+
+use super::{Bias, Edge, EdgeEvent, EdgeStream, GpioBackend, LineConfig, OutputLine};
+use crate::Void;
+
+/// Backend for the Linux GPIO character device.
+///
+/// This uses the v1 uAPI, which has no debounce support, so every debounce
+/// window configured for these lines is applied in software.
+pub struct CdevBackend {
+    chip: Chip,
+}
+
+impl CdevBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(CdevBackend {
+            chip: Chip::new(path)?,
+        })
+    }
+}
+
+/// `GPIOHANDLE_REQUEST_BIAS_*`, which gpio-cdev doesn't know about yet.
+const BIAS_PULL_UP: u32 = 1 << 5;
+const BIAS_PULL_DOWN: u32 = 1 << 6;
+const BIAS_DISABLE: u32 = 1 << 7;
+
+fn request_flags(direction: LineRequestFlags, config: LineConfig) -> LineRequestFlags {
+    let mut flags = direction;
+    if config.active_low {
+        flags |= LineRequestFlags::ACTIVE_LOW;
+    }
+    let bias = match config.bias {
+        Bias::AsIs => return flags,
+        Bias::PullUp => BIAS_PULL_UP,
+        Bias::PullDown => BIAS_PULL_DOWN,
+        Bias::Disable => BIAS_DISABLE,
+    };
+    // SAFETY: the flags are only handed to the kernel, which validates them
+    // and rejects the request on kernels without bias support.
+    flags | unsafe { LineRequestFlags::from_bits_unchecked(bias) }
+}
+
+struct CdevOutput(LineHandle);
+
+impl OutputLine for CdevOutput {
+    fn set_value(&self, value: bool) -> Void {
+        self.0.set_value(u8::from(value))?;
+        Ok(())
+    }
+
+    fn value(&self) -> anyhow::Result<bool> {
+        Ok(self.0.get_value()? != 0)
+    }
+}
+
+impl GpioBackend for CdevBackend {
+    fn num_lines(&self) -> u32 {
+        self.chip.num_lines()
+    }
+
+    fn request_output(
+        &mut self,
+        line: u32,
+        default: bool,
+        config: LineConfig,
+        consumer: &str,
+    ) -> anyhow::Result<Box<dyn OutputLine>> {
+        let handle = self.chip.get_line(line)?.request(
+            request_flags(LineRequestFlags::OUTPUT, config),
+            u8::from(default),
+            consumer,
+        )?;
+        Ok(Box::new(CdevOutput(handle)))
+    }
+
+    fn read_input(
+        &mut self,
+        line: u32,
+        config: LineConfig,
+        consumer: &str,
+    ) -> anyhow::Result<bool> {
+        let handle = self.chip.get_line(line)?.request(
+            request_flags(LineRequestFlags::INPUT, config),
+            0,
+            consumer,
+        )?;
+        Ok(handle.get_value()? != 0)
+    }
+
+    fn request_events(
+        &mut self,
+        line: u32,
+        config: LineConfig,
+        consumer: &str,
+    ) -> anyhow::Result<EdgeStream> {
+        let handle = self.chip.get_line(line)?;
+        let evt = AsyncLineEventHandle::new(handle.events(
+            request_flags(LineRequestFlags::INPUT, config),
+            EventRequestFlags::BOTH_EDGES,
+            consumer,
+        )?)?;
+        Ok(Box::pin(evt.map(move |event| {
+            let info = event?;
+            let edge = match info.event_type() {
+                EventType::RisingEdge => Edge::Rising,
+                EventType::FallingEdge => Edge::Falling,
+            };
+            Ok(EdgeEvent {
+                line,
+                edge,
+                timestamp: info.timestamp(),
+            })
+        })))
+    }
+}
@@ -0,0 +1,108 @@
+//! Hardware abstraction for the GPIO lines radIO drives and listens to.
+//!
+//! Everything above this module talks to a [`GpioBackend`] instead of a
+//! `gpio_cdev::Chip`, so the binding and dispatch code can run against the
+//! in-memory [`SimBackend`] on machines without GPIO hardware.
+
+mod cdev;
+mod sim;
+
+use std::{pin::Pin, time::Duration};
+
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+
+use crate::Void;
+
+pub use cdev::CdevBackend;
+pub use sim::SimBackend;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+impl Edge {
+    /// The name used in the config and by the control interfaces.
+    pub fn name(self) -> &'static str {
+        match self {
+            Edge::Rising => "rising",
+            Edge::Falling => "falling",
+        }
+    }
+}
+
+/// A single edge reported on an input line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeEvent {
+    pub line: u32,
+    pub edge: Edge,
+    /// Kernel timestamp of the edge in nanoseconds.
+    pub timestamp: u64,
+}
+
+/// Internal pull resistor setting. Needs Linux 5.5 or later for anything but `as-is`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bias {
+    #[default]
+    AsIs,
+    PullUp,
+    PullDown,
+    Disable,
+}
+
+/// Electrical settings for a requested line.
+///
+/// With `active_low` set, output values and reported edges are logical: an
+/// active low line driven to `true` sits at 0V, and pulling it to ground is
+/// reported as a rising edge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LineConfig {
+    pub active_low: bool,
+    pub bias: Bias,
+}
+
+pub type EdgeStream = Pin<Box<dyn Stream<Item = anyhow::Result<EdgeEvent>> + Send>>;
+
+/// A requested output line. The line is released when the handle is dropped.
+pub trait OutputLine: Send + Sync {
+    fn set_value(&self, value: bool) -> Void;
+    fn value(&self) -> anyhow::Result<bool>;
+}
+
+pub trait GpioBackend: Send {
+    /// Number of lines the chip exposes.
+    fn num_lines(&self) -> u32;
+
+    /// Request `line` as an output, driving it to `default` right away.
+    fn request_output(
+        &mut self,
+        line: u32,
+        default: bool,
+        config: LineConfig,
+        consumer: &str,
+    ) -> anyhow::Result<Box<dyn OutputLine>>;
+
+    /// Request `line` as an input and stream both rising and falling edges.
+    fn request_events(
+        &mut self,
+        line: u32,
+        config: LineConfig,
+        consumer: &str,
+    ) -> anyhow::Result<EdgeStream>;
+
+    /// Read the logical level of `line` without holding on to it.
+    fn read_input(&mut self, line: u32, config: LineConfig, consumer: &str)
+        -> anyhow::Result<bool>;
+
+    /// Ask the hardware to debounce `line` before it reports edges.
+    ///
+    /// Returns `false` if the backend can't, in which case the caller has to
+    /// filter the edge stream in software.
+    fn set_debounce(&mut self, _line: u32, _period: Duration) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
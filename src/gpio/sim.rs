@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail};
+use futures::{channel::mpsc, stream::StreamExt};
+
+use super::{Edge, EdgeEvent, EdgeStream, GpioBackend, LineConfig, OutputLine};
+use crate::Void;
+
+struct SimInput {
+    tx: mpsc::UnboundedSender<anyhow::Result<EdgeEvent>>,
+    active_low: bool,
+}
+
+#[derive(Default)]
+struct SimState {
+    num_lines: u32,
+    requested: HashSet<u32>,
+    outputs: HashMap<u32, bool>,
+    inputs: HashMap<u32, SimInput>,
+}
+
+/// In-memory GPIO chip.
+///
+/// Clones share the same state, so a test can keep one handle to inject
+/// edges and read back output levels while the daemon owns another.
+#[derive(Clone, Default)]
+pub struct SimBackend {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimBackend {
+    pub fn new(num_lines: u32) -> Self {
+        SimBackend {
+            state: Arc::new(Mutex::new(SimState {
+                num_lines,
+                ..SimState::default()
+            })),
+        }
+    }
+
+    /// Deliver an edge on a line that has been requested for events.
+    ///
+    /// `edge` is the electrical edge; it is inverted for active low lines.
+    /// The simulated chip can't debounce, that is left to the software
+    /// filter.
+    pub fn inject(&self, line: u32, edge: Edge, timestamp: u64) -> Void {
+        let state = self.state.lock().unwrap();
+        let active_low = match state.inputs.get(&line) {
+            Some(input) => input.active_low,
+            None => bail!("GPIO {} is not requested as input", line),
+        };
+        let edge = match (edge, active_low) {
+            (edge, false) => edge,
+            (Edge::Rising, true) => Edge::Falling,
+            (Edge::Falling, true) => Edge::Rising,
+        };
+        let event = EdgeEvent {
+            line,
+            edge,
+            timestamp,
+        };
+        state.inputs[&line]
+            .tx
+            .unbounded_send(Ok(event))
+            .map_err(|_| anyhow!("GPIO {} event stream was dropped", line))
+    }
+
+    /// Have the event stream of an input line report an error, like a failed
+    /// read from the kernel.
+    pub fn fail(&self, line: u32, message: &str) -> Void {
+        let state = self.state.lock().unwrap();
+        let input = state
+            .inputs
+            .get(&line)
+            .ok_or_else(|| anyhow!("GPIO {} is not requested as input", line))?;
+        input
+            .tx
+            .unbounded_send(Err(anyhow!("{}", message)))
+            .map_err(|_| anyhow!("GPIO {} event stream was dropped", line))
+    }
+
+    /// Electrical level of an output line, `None` if it is not requested.
+    pub fn output(&self, line: u32) -> Option<bool> {
+        self.state.lock().unwrap().outputs.get(&line).copied()
+    }
+
+    /// Lines currently held by a consumer.
+    pub fn requested(&self) -> HashSet<u32> {
+        self.state.lock().unwrap().requested.clone()
+    }
+
+    fn claim(&self, state: &mut SimState, line: u32) -> Void {
+        if line >= state.num_lines {
+            bail!(
+                "GPIO {} is out of range (chip has {} lines)",
+                line,
+                state.num_lines
+            );
+        }
+        if !state.requested.insert(line) {
+            bail!("GPIO {} is busy", line);
+        }
+        Ok(())
+    }
+}
+
+struct SimOutput {
+    line: u32,
+    active_low: bool,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl OutputLine for SimOutput {
+    fn set_value(&self, value: bool) -> Void {
+        self.state
+            .lock()
+            .unwrap()
+            .outputs
+            .insert(self.line, value != self.active_low);
+        Ok(())
+    }
+
+    fn value(&self) -> anyhow::Result<bool> {
+        Ok(self.state.lock().unwrap().outputs[&self.line] != self.active_low)
+    }
+}
+
+impl Drop for SimOutput {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.requested.remove(&self.line);
+        state.outputs.remove(&self.line);
+    }
+}
+
+/// Releases an input line once its event stream is dropped.
+struct SimInputGuard {
+    line: u32,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Drop for SimInputGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.requested.remove(&self.line);
+        state.inputs.remove(&self.line);
+    }
+}
+
+impl GpioBackend for SimBackend {
+    fn num_lines(&self) -> u32 {
+        self.state.lock().unwrap().num_lines
+    }
+
+    fn request_output(
+        &mut self,
+        line: u32,
+        default: bool,
+        config: LineConfig,
+        _consumer: &str,
+    ) -> anyhow::Result<Box<dyn OutputLine>> {
+        let mut state = self.state.lock().unwrap();
+        self.claim(&mut state, line)?;
+        state.outputs.insert(line, default != config.active_low);
+        Ok(Box::new(SimOutput {
+            line,
+            active_low: config.active_low,
+            state: self.state.clone(),
+        }))
+    }
+
+    /// Inputs of the simulated chip rest at their inactive level.
+    fn read_input(
+        &mut self,
+        line: u32,
+        _config: LineConfig,
+        _consumer: &str,
+    ) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        self.claim(&mut state, line)?;
+        state.requested.remove(&line);
+        Ok(false)
+    }
+
+    fn request_events(
+        &mut self,
+        line: u32,
+        config: LineConfig,
+        _consumer: &str,
+    ) -> anyhow::Result<EdgeStream> {
+        let mut state = self.state.lock().unwrap();
+        self.claim(&mut state, line)?;
+        let (tx, rx) = mpsc::unbounded();
+        state.inputs.insert(
+            line,
+            SimInput {
+                tx,
+                active_low: config.active_low,
+            },
+        );
+        let guard = SimInputGuard {
+            line,
+            state: self.state.clone(),
+        };
+        Ok(Box::pin(rx.map(move |event| {
+            // The guard lives in the closure and releases the line with the stream.
+            let _guard = &guard;
+            event
+        })))
+    }
+}
@@ -0,0 +1,224 @@
+//! Embedded HTTP server for field debugging: a status page with the pin map
+//! and virtual buttons, and the JSON API behind it.
+//!
+//! * `GET /api/config`: the applied config.
+//! * `GET /api/levels`: the levels of the held lines.
+//! * `GET /api/events`: the most recent events, oldest first.
+//! * `POST /api/trigger` with `{"input": "gpio17", "gesture": "long"}`: run
+//!   the action bound to a gesture, as if the input was pressed. With
+//!   `{"input": "volume", "turn": "clockwise"}` an encoder is turned.
+//! * `POST /api/set` with `{"output": 5, "value": true}`: drive an output.
+//! * `GET /api/stream`: a WebSocket carrying every event as it happens,
+//!   filtered by pin or action, see the `stream` module.
+//! * `GET /metrics`: Prometheus metrics, see the `metrics` module.
+//!
+//! Answers are the JSON documents of the control socket.
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use warp::{http::StatusCode, Filter, Reply};
+
+use crate::{
+    control::{Control, Event, Request, Response},
+    encoder::Direction,
+    input::Gesture,
+    metrics::Metrics,
+};
+
+mod stream;
+
+const PAGE: &str = include_str!("status.html");
+
+/// ```toml
+/// [http]
+/// enabled = true
+/// bind = "0.0.0.0"
+/// port = 8080
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_bind")]
+    pub bind: IpAddr,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// How many events `/api/events` keeps.
+    #[serde(default = "default_history")]
+    pub history: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: false,
+            bind: default_bind(),
+            port: default_port(),
+            history: default_history(),
+        }
+    }
+}
+
+fn default_bind() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+fn default_history() -> usize {
+    100
+}
+
+/// The running server. Dropping it closes the port.
+pub struct Server {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Trigger {
+    input: String,
+    #[serde(default)]
+    gesture: Gesture,
+    #[serde(default)]
+    turn: Option<Direction>,
+}
+
+#[derive(Deserialize)]
+struct Set {
+    output: u32,
+    value: bool,
+}
+
+type History = Arc<Mutex<VecDeque<Event>>>;
+
+/// Bind the configured address and serve `control` and `metrics` on it.
+pub fn serve(cfg: &HttpConfig, control: Control, metrics: Metrics) -> anyhow::Result<Server> {
+    let history = History::default();
+    let recorder = tokio::spawn(record(control.clone(), history.clone(), cfg.history));
+
+    let with_control = warp::any().map(move || control.clone());
+    let page = warp::path::end()
+        .and(warp::get())
+        .map(|| warp::reply::html(PAGE));
+    let config = warp::path!("api" / "config")
+        .and(warp::get())
+        .map(|| Request::Bindings)
+        .and(with_control.clone())
+        .and_then(request);
+    let levels = warp::path!("api" / "levels")
+        .and(warp::get())
+        .map(|| Request::Levels)
+        .and(with_control.clone())
+        .and_then(request);
+    let events = warp::path!("api" / "events").and(warp::get()).map(move || {
+        let history = history.lock().unwrap();
+        warp::reply::json(&history.iter().collect::<Vec<_>>())
+    });
+    let trigger = warp::path!("api" / "trigger")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(|trigger: Trigger| Request::Trigger {
+            input: trigger.input,
+            gesture: trigger.gesture,
+            turn: trigger.turn,
+        })
+        .and(with_control.clone())
+        .and_then(request);
+    let set = warp::path!("api" / "set")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(|set: Set| Request::Set {
+            output: set.output,
+            value: set.value,
+        })
+        .and(with_control.clone())
+        .and_then(request);
+    let live = warp::path!("api" / "stream")
+        .and(warp::ws())
+        .and(warp::query())
+        .and(with_control.clone())
+        .map(stream::upgrade);
+    let scrape = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_control)
+        .and(warp::any().map(move || metrics.clone()))
+        .and_then(render_metrics);
+
+    let routes = page
+        .or(config)
+        .or(levels)
+        .or(events)
+        .or(trigger)
+        .or(set)
+        .or(live)
+        .or(scrape);
+    let (addr, server) = warp::serve(routes).try_bind_ephemeral((cfg.bind, cfg.port))?;
+    info!("Status page at http://{}", addr);
+    Ok(Server {
+        tasks: vec![recorder, tokio::spawn(server)],
+    })
+}
+
+async fn request(request: Request, control: Control) -> Result<impl Reply, Infallible> {
+    let response = control.request(request).await;
+    let status = match response {
+        Response::Error { .. } => StatusCode::BAD_REQUEST,
+        _ => StatusCode::OK,
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+async fn render_metrics(control: Control, metrics: Metrics) -> Result<impl Reply, Infallible> {
+    let text = match control.request(Request::Levels).await {
+        Response::Levels { outputs, pwm, .. } => metrics.render(&outputs, &pwm),
+        _ => Err(anyhow::anyhow!("The daemon didn't report the line levels")),
+    };
+    let reply = match text {
+        Ok(text) => warp::reply::with_status(text, StatusCode::OK),
+        Err(e) => warp::reply::with_status(format!("{:#}", e), StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    Ok(warp::reply::with_header(
+        reply,
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// Keep the last `size` events in `history`.
+async fn record(control: Control, history: History, size: usize) {
+    let mut events = control.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let mut history = history.lock().unwrap();
+                history.push_back(event);
+                while history.len() > size {
+                    history.pop_front();
+                }
+            }
+            Err(RecvError::Lagged(missed)) => warn!("Event history missed {} events", missed),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Live event stream over a WebSocket, for bench tools that watch the
+//! buttons in real time.
+//!
+//! `GET /api/stream` upgrades to a WebSocket that carries one JSON text
+//! message per event, the same documents as `/api/events`. Edge events
+//! carry the pin, edge and kernel timestamp. Press events also carry the
+//! gesture, the action that ran and its error, if any. The query narrows
+//! the stream down, e.g. `?pin=17,27&action=mpd-next`. An `action` filter
+//! only passes presses.
+
+use std::collections::HashSet;
+
+use futures::{SinkExt, StreamExt};
+use log::debug;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use warp::{
+    http::StatusCode,
+    ws::{Message, WebSocket, Ws},
+    Reply,
+};
+
+use crate::{
+    config::pin_number,
+    control::{Control, Event},
+};
+
+/// The query, lists are comma separated.
+#[derive(Deserialize, Debug, Default)]
+pub struct Query {
+    pin: Option<String>,
+    action: Option<String>,
+}
+
+struct Filter {
+    pins: Option<HashSet<u32>>,
+    actions: Option<HashSet<String>>,
+}
+
+impl Filter {
+    fn parse(query: &Query) -> anyhow::Result<Self> {
+        let pins = match &query.pin {
+            Some(pins) => Some(
+                list(pins)
+                    .map(|pin| Ok(pin_number(pin)?))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            None => None,
+        };
+        Ok(Filter {
+            pins,
+            actions: query
+                .action
+                .as_ref()
+                .map(|actions| list(actions).map(str::to_string).collect()),
+        })
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        let (pin, action) = match event {
+            Event::Edge { pin, .. } => (pin, None),
+            Event::Press { pin, action, .. } => (pin, action.as_ref()),
+        };
+        self.pins.as_ref().is_none_or(|pins| pins.contains(pin))
+            && self
+                .actions
+                .as_ref()
+                .is_none_or(|actions| action.is_some_and(|action| actions.contains(action)))
+    }
+}
+
+fn list(items: &str) -> impl Iterator<Item = &str> {
+    items
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Upgrade to a stream of the events `query` asks for.
+pub fn upgrade(ws: Ws, query: Query, control: Control) -> warp::reply::Response {
+    match Filter::parse(&query) {
+        Ok(filter) => ws
+            .on_upgrade(move |socket| stream(socket, filter, control))
+            .into_response(),
+        Err(e) => {
+            warp::reply::with_status(format!("Invalid filter: {:#}", e), StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    }
+}
+
+async fn stream(socket: WebSocket, filter: Filter, control: Control) {
+    let (mut tx, mut rx) = socket.split();
+    let mut events = control.subscribe();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if filter.matches(&event) => {
+                    let text = match serde_json::to_string(&event) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            debug!("Failed to serialise {:?}: {}", event, e);
+                            continue;
+                        }
+                    };
+                    if tx.send(Message::text(text)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => debug!("Event stream missed {} events", missed),
+                Err(RecvError::Closed) => return,
+            },
+            // Nothing to read, but a closed socket ends the stream.
+            message = rx.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => return,
+            },
+        }
+    }
+}
@@ -0,0 +1,542 @@
+//! Filters applied to raw edge streams before they reach the dispatcher, and
+//! the press gesture recognition on top of them.
+
+use std::{pin::Pin, str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+use futures::{
+    future,
+    stream::{self, StreamExt},
+    Stream,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Instant};
+
+use crate::gpio::{Edge, EdgeEvent, EdgeStream};
+
+/// Glitch filter for a single line.
+///
+/// A new level is passed on once the line has held it for `window`, so
+/// bounces and taps shorter than the window never get through. The edge
+/// passed on is the last one before the line settled, with its timestamp.
+///
+/// Whether the line held its level until now is up to the caller, who
+/// calls [`Debounce::settle`] once `window` passed without an edge.
+#[derive(Debug, Clone)]
+pub struct Debounce {
+    window: u64,
+    /// Where the last edge passed on left the line.
+    level: Option<Edge>,
+    /// The last raw edge, while the line may still bounce.
+    pending: Option<EdgeEvent>,
+}
+
+impl Debounce {
+    pub fn new(window: Duration) -> Self {
+        Debounce {
+            window: window.as_nanos() as u64,
+            level: None,
+            pending: None,
+        }
+    }
+
+    /// Take a raw edge. Returns the pending edge if the line had settled
+    /// on it before this one came.
+    pub fn on_edge(&mut self, event: &EdgeEvent) -> Option<EdgeEvent> {
+        if self.level.is_none() {
+            // Before its first edge the line was at the other level.
+            self.level = Some(match event.edge {
+                Edge::Rising => Edge::Falling,
+                Edge::Falling => Edge::Rising,
+            });
+        }
+        let settled = match self.pending {
+            Some(pending) if event.timestamp.saturating_sub(pending.timestamp) >= self.window => {
+                self.settle()
+            }
+            _ => None,
+        };
+        self.pending = Some(*event);
+        settled
+    }
+
+    /// The line kept its level for `window`. Returns the pending edge if it
+    /// left the line at a new level.
+    pub fn settle(&mut self) -> Option<EdgeEvent> {
+        let pending = self.pending.take()?;
+        if self.level == Some(pending.edge) {
+            return None;
+        }
+        self.level = Some(pending.edge);
+        Some(pending)
+    }
+}
+
+/// Wrap `events` in a software [`Debounce`] filter. Edges come out `window`
+/// late, once the line settled.
+pub fn debounce(events: EdgeStream, window: Duration) -> EdgeStream {
+    let state = (events, Debounce::new(window), None::<Instant>);
+    Box::pin(stream::unfold(
+        state,
+        move |(mut events, mut filter, mut deadline)| async move {
+            loop {
+                let timeout = async {
+                    match deadline {
+                        Some(deadline) => time::sleep_until(deadline).await,
+                        None => future::pending().await,
+                    }
+                };
+                let event = tokio::select! {
+                    event = events.next() => match event {
+                        Some(Ok(event)) => {
+                            deadline = Some(Instant::now() + window);
+                            filter.on_edge(&event).map(Ok)
+                        }
+                        Some(Err(e)) => Some(Err(e)),
+                        None => return None,
+                    },
+                    _ = timeout => {
+                        deadline = None;
+                        filter.settle().map(Ok)
+                    }
+                };
+                if let Some(event) = event {
+                    return Some((event, (events, filter, deadline)));
+                }
+            }
+        },
+    ))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Gesture {
+    #[default]
+    Short,
+    Long,
+    Double,
+}
+
+impl Gesture {
+    /// The name used in the config and by the control interfaces.
+    pub fn name(self) -> &'static str {
+        match self {
+            Gesture::Short => "short",
+            Gesture::Long => "long",
+            Gesture::Double => "double",
+        }
+    }
+}
+
+impl FromStr for Gesture {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "short" => Ok(Gesture::Short),
+            "long" => Ok(Gesture::Long),
+            "double" => Ok(Gesture::Double),
+            _ => Err(anyhow!("expected short, long or double, not {:?}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GestureEvent {
+    pub line: u32,
+    pub gesture: Gesture,
+    /// The edge that completed the gesture, `None` if a timer did.
+    pub edge: Option<Edge>,
+    /// Kernel timestamp of the edge that completed the gesture, or the
+    /// moment its timer ran out, in nanoseconds.
+    pub timestamp: u64,
+}
+
+pub type GestureStream = Pin<Box<dyn Stream<Item = anyhow::Result<GestureEvent>> + Send>>;
+
+/// Which logical edges fire a binding that has no long or double press.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum EdgeSelect {
+    #[default]
+    Rising,
+    Falling,
+    Both,
+}
+
+impl EdgeSelect {
+    pub fn matches(self, edge: Edge) -> bool {
+        matches!(
+            (self, edge),
+            (EdgeSelect::Both, _)
+                | (EdgeSelect::Rising, Edge::Rising)
+                | (EdgeSelect::Falling, Edge::Falling)
+        )
+    }
+}
+
+/// Which gestures a line recognises. `None` means the gesture isn't bound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GestureConfig {
+    /// Hold time for a long press.
+    pub long: Option<Duration>,
+    /// Window for the second press of a double press.
+    pub double: Option<Duration>,
+    /// Edges that fire a short press while no other gesture is bound.
+    pub trigger: EdgeSelect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timer {
+    Long,
+    Short,
+}
+
+/// Turns the logical edges of a button into presses: a rising edge is the
+/// button going down, a falling edge is its release.
+///
+/// Edge to edge intervals are measured with the kernel timestamps. Gestures
+/// that complete without a further edge (a long press while still held, a
+/// short press once no second press came) are armed as a timer that the
+/// caller fires through [`Recognizer::on_timer`].
+#[derive(Debug, Clone)]
+pub struct Recognizer {
+    cfg: GestureConfig,
+    line: u32,
+    pressed_at: Option<u64>,
+    consumed: bool,
+    pending_short: bool,
+    last: u64,
+    timer: Option<(Timer, Duration)>,
+}
+
+impl Recognizer {
+    pub fn new(cfg: GestureConfig) -> Self {
+        Recognizer {
+            cfg,
+            line: 0,
+            pressed_at: None,
+            consumed: false,
+            pending_short: false,
+            last: 0,
+            timer: None,
+        }
+    }
+
+    /// The timer armed by the last edge that changed the state, relative to
+    /// that edge.
+    pub fn timer(&self) -> Option<Duration> {
+        self.timer.map(|(_, after)| after)
+    }
+
+    /// Whether `edge` changes the state. A second edge in the same
+    /// direction, as bouncing contacts report without a debounce, leaves
+    /// the state and the armed timer alone.
+    fn changes(&self, edge: Edge) -> bool {
+        if self.cfg.long.is_none() && self.cfg.double.is_none() {
+            return true;
+        }
+        match edge {
+            Edge::Rising => self.pressed_at.is_none(),
+            Edge::Falling => self.pressed_at.is_some(),
+        }
+    }
+
+    pub fn on_edge(&mut self, event: &EdgeEvent) -> Option<GestureEvent> {
+        self.line = event.line;
+        if !self.changes(event.edge) {
+            return None;
+        }
+        self.last = event.timestamp;
+        self.timer = None;
+        if self.cfg.long.is_none() && self.cfg.double.is_none() {
+            let fires = self.cfg.trigger.matches(event.edge);
+            return fires.then(|| self.event(Gesture::Short, Some(event.edge), event.timestamp));
+        }
+        let gesture = match event.edge {
+            Edge::Rising if self.pending_short => {
+                self.pending_short = false;
+                self.pressed_at = Some(event.timestamp);
+                self.consumed = true;
+                Some(Gesture::Double)
+            }
+            Edge::Rising => {
+                self.pressed_at = Some(event.timestamp);
+                self.consumed = false;
+                self.timer = self.cfg.long.map(|hold| (Timer::Long, hold));
+                None
+            }
+            Edge::Falling => self.on_release(event.timestamp),
+        };
+        gesture.map(|gesture| self.event(gesture, Some(event.edge), event.timestamp))
+    }
+
+    fn on_release(&mut self, timestamp: u64) -> Option<Gesture> {
+        let pressed_at = self.pressed_at.take()?;
+        if self.consumed {
+            return None;
+        }
+        let held = Duration::from_nanos(timestamp.saturating_sub(pressed_at));
+        match self.cfg {
+            GestureConfig {
+                long: Some(hold), ..
+            } if held >= hold => Some(Gesture::Long),
+            GestureConfig {
+                double: Some(window),
+                ..
+            } => {
+                self.pending_short = true;
+                self.timer = Some((Timer::Short, window));
+                None
+            }
+            _ => Some(Gesture::Short),
+        }
+    }
+
+    /// Fire the armed timer.
+    pub fn on_timer(&mut self) -> Option<GestureEvent> {
+        let (timer, after) = self.timer.take()?;
+        let gesture = match timer {
+            Timer::Long if self.pressed_at.is_some() && !self.consumed => {
+                self.consumed = true;
+                Gesture::Long
+            }
+            Timer::Short if self.pending_short => {
+                self.pending_short = false;
+                Gesture::Short
+            }
+            _ => return None,
+        };
+        Some(self.event(gesture, None, self.last + after.as_nanos() as u64))
+    }
+
+    fn event(&self, gesture: Gesture, edge: Option<Edge>, timestamp: u64) -> GestureEvent {
+        GestureEvent {
+            line: self.line,
+            gesture,
+            edge,
+            timestamp,
+        }
+    }
+}
+
+/// Recognise the gestures in `cfg` on an edge stream.
+pub fn gestures(events: EdgeStream, cfg: GestureConfig) -> GestureStream {
+    let state = (events, Recognizer::new(cfg), None::<Instant>);
+    Box::pin(stream::unfold(
+        state,
+        |(mut events, mut recognizer, mut deadline)| async move {
+            loop {
+                let timeout = async {
+                    match deadline {
+                        Some(deadline) => time::sleep_until(deadline).await,
+                        None => future::pending().await,
+                    }
+                };
+                let gesture = tokio::select! {
+                    event = events.next() => match event {
+                        Some(Ok(event)) => {
+                            let changes = recognizer.changes(event.edge);
+                            let gesture = recognizer.on_edge(&event);
+                            if changes {
+                                deadline = recognizer.timer().map(|after| Instant::now() + after);
+                            }
+                            gesture.map(Ok)
+                        }
+                        Some(Err(e)) => Some(Err(e)),
+                        None => return None,
+                    },
+                    _ = timeout => {
+                        deadline = None;
+                        recognizer.on_timer().map(Ok)
+                    }
+                };
+                if let Some(gesture) = gesture {
+                    return Some((gesture, (events, recognizer, deadline)));
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    fn edge(edge: Edge, at_ms: u64) -> EdgeEvent {
+        EdgeEvent {
+            line: 17,
+            edge,
+            timestamp: at_ms * MS,
+        }
+    }
+
+    /// Run raw edges through a filter, with the line settling after the
+    /// last one.
+    fn debounced(window_ms: u64, edges: &[EdgeEvent]) -> Vec<EdgeEvent> {
+        let mut filter = Debounce::new(Duration::from_millis(window_ms));
+        let mut passed: Vec<EdgeEvent> = edges.iter().filter_map(|e| filter.on_edge(e)).collect();
+        passed.extend(filter.settle());
+        passed
+    }
+
+    #[test]
+    fn bounces_settle_on_the_last_edge() {
+        let raw = [
+            edge(Edge::Rising, 0),
+            edge(Edge::Falling, 1),
+            edge(Edge::Rising, 2),
+            edge(Edge::Falling, 300),
+            edge(Edge::Rising, 301),
+            edge(Edge::Falling, 303),
+        ];
+        assert_eq!(
+            debounced(10, &raw),
+            vec![edge(Edge::Rising, 2), edge(Edge::Falling, 303)]
+        );
+    }
+
+    #[test]
+    fn a_tap_shorter_than_the_window_is_dropped_whole() {
+        let raw = [
+            edge(Edge::Rising, 0),
+            edge(Edge::Falling, 4),
+            edge(Edge::Rising, 500),
+            edge(Edge::Falling, 650),
+            edge(Edge::Rising, 900),
+            edge(Edge::Falling, 1000),
+        ];
+        assert_eq!(
+            debounced(10, &raw),
+            vec![
+                edge(Edge::Rising, 500),
+                edge(Edge::Falling, 650),
+                edge(Edge::Rising, 900),
+                edge(Edge::Falling, 1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_bounce_back_to_the_level_is_not_an_edge() {
+        let raw = [
+            edge(Edge::Rising, 0),
+            edge(Edge::Falling, 100),
+            edge(Edge::Rising, 102),
+        ];
+        assert_eq!(debounced(10, &raw), vec![edge(Edge::Rising, 0)]);
+    }
+
+    fn recognizer(long_ms: Option<u64>, double_ms: Option<u64>) -> Recognizer {
+        Recognizer::new(GestureConfig {
+            long: long_ms.map(Duration::from_millis),
+            double: double_ms.map(Duration::from_millis),
+            trigger: EdgeSelect::Rising,
+        })
+    }
+
+    fn gesture(event: Option<GestureEvent>) -> Option<Gesture> {
+        event.map(|event| event.gesture)
+    }
+
+    #[test]
+    fn short_press_fires_on_the_trigger_edge() {
+        let mut r = recognizer(None, None);
+        let event = r.on_edge(&edge(Edge::Rising, 0)).unwrap();
+        assert_eq!(event.gesture, Gesture::Short);
+        assert_eq!(event.edge, Some(Edge::Rising));
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 50)), None);
+        assert_eq!(r.timer(), None);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut r = recognizer(Some(500), None);
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 0)), None);
+        assert_eq!(r.timer(), Some(Duration::from_millis(500)));
+        let event = r.on_timer().unwrap();
+        assert_eq!(event.gesture, Gesture::Long);
+        assert_eq!(event.edge, None);
+        assert_eq!(event.timestamp, 500 * MS);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 900)), None);
+
+        // A release that beats a late timer still counts as long.
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 2000)), None);
+        let long = gesture(r.on_edge(&edge(Edge::Falling, 2600)));
+        assert_eq!(long, Some(Gesture::Long));
+
+        // Released in time, a short press fires on release.
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 3000)), None);
+        let short = gesture(r.on_edge(&edge(Edge::Falling, 3100)));
+        assert_eq!(short, Some(Gesture::Short));
+        assert_eq!(r.timer(), None);
+    }
+
+    #[test]
+    fn double_press() {
+        let mut r = recognizer(None, Some(300));
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 0)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 50)), None);
+        assert_eq!(r.timer(), Some(Duration::from_millis(300)));
+        let double = gesture(r.on_edge(&edge(Edge::Rising, 200)));
+        assert_eq!(double, Some(Gesture::Double));
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 250)), None);
+        assert_eq!(r.timer(), None);
+
+        // No second press in the window makes it a short press.
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 1000)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 1050)), None);
+        let event = r.on_timer().unwrap();
+        assert_eq!(event.gesture, Gesture::Short);
+        assert_eq!(event.timestamp, 1350 * MS);
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 1400)), None);
+    }
+
+    #[test]
+    fn long_and_double_press() {
+        let mut r = recognizer(Some(500), Some(300));
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 0)), None);
+        assert_eq!(gesture(r.on_timer()), Some(Gesture::Long));
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 700)), None);
+        assert_eq!(r.timer(), None);
+
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 1000)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 1100)), None);
+        let double = gesture(r.on_edge(&edge(Edge::Rising, 1200)));
+        assert_eq!(double, Some(Gesture::Double));
+        // The second press of a double press is never long.
+        assert_eq!(r.timer(), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 1900)), None);
+
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 3000)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 3100)), None);
+        assert_eq!(gesture(r.on_timer()), Some(Gesture::Short));
+    }
+
+    #[test]
+    fn repeated_edges_keep_the_timer() {
+        let mut r = recognizer(Some(500), Some(300));
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 0)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 10)), None);
+        assert_eq!(r.timer(), Some(Duration::from_millis(500)));
+        let event = r.on_timer().unwrap();
+        assert_eq!(event.gesture, Gesture::Long);
+        assert_eq!(event.timestamp, 500 * MS);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 700)), None);
+
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 1000)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 1100)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 1110)), None);
+        assert_eq!(r.timer(), Some(Duration::from_millis(300)));
+        let event = r.on_timer().unwrap();
+        assert_eq!(event.gesture, Gesture::Short);
+        assert_eq!(event.timestamp, 1400 * MS);
+
+        // The pending short press is gone, a later press starts afresh.
+        assert_eq!(r.on_edge(&edge(Edge::Rising, 5000)), None);
+        assert_eq!(r.on_edge(&edge(Edge::Falling, 5100)), None);
+        assert_eq!(gesture(r.on_timer()), Some(Gesture::Short));
+    }
+}
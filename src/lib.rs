@@ -0,0 +1,24 @@
+pub mod action;
+pub mod command;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod dbus;
+pub mod dispatch;
+pub mod effect;
+pub mod encoder;
+pub mod gpio;
+pub mod http;
+pub mod input;
+pub mod metrics;
+pub mod mpd;
+pub mod mpris;
+pub mod mqtt;
+pub mod notify;
+pub mod power;
+pub mod pwm;
+pub mod socket;
+pub mod validate;
+pub mod watchdog;
+
+pub type Void = anyhow::Result<()>;
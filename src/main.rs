@@ -1,228 +1,205 @@
-use logind_zbus::ManagerProxy;
-use zbus::Connection;
-
-use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::Read,
-    thread,
-    time::Duration,
+use std::{fs::File, path::PathBuf};
+
+use anyhow::{anyhow, bail};
+use log::{error, info, warn};
+use rad_io::{
+    config::{
+        default_config_toml, load_config, log_level_to_enum, read_config, AppConfig, CFGPATH,
+    },
+    control::Request,
+    daemon::Daemon,
+    dispatch::Context,
+    encoder::Direction,
+    gpio::{CdevBackend, GpioBackend},
+    input::Gesture,
+    power,
+    socket::{self, DEFAULT_SOCKET},
+    validate::validate,
+    Void,
 };
 
-use futures::stream::StreamExt;
-use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, EventType, LineRequestFlags};
-use log::{debug, error, info, warn, LevelFilter};
-use serde::{Deserialize, Serialize};
-
-use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode, WriteLogger};
-
-const DEFAULT_CHIP: &str = "/dev/gpiochip0";
-const CFGPATH: &str = "/etc/radio.conf";
-
-type Void = anyhow::Result<()>;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct AppConfig {
-    pub master_chip: String,
-    pub log_level: u8,
-    pub input_binding: HashMap<String, String>,
-    pub output_binding: HashMap<String, String>,
+use simplelog::{
+    ColorChoice, CombinedLogger, Config, SharedLogger, TermLogger, TerminalMode, WriteLogger,
+};
+use structopt::StructOpt;
+
+/// GPIO manager for a DIY jukebox
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rad_io")]
+struct Opt {
+    /// Config file to use. It is created with defaults if it doesn't exist.
+    #[structopt(short, long, parse(from_os_str), default_value = CFGPATH)]
+    config: PathBuf,
+    /// Only validate the config, exit with an error if it has problems
+    #[structopt(long)]
+    check_config: bool,
+    /// Print the default config and exit
+    #[structopt(long)]
+    print_default_config: bool,
+    /// Override the log level from the config (0 = off ... 5 = trace)
+    #[structopt(short, long)]
+    log_level: Option<u8>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-impl Default for AppConfig {
-    fn default() -> Self {
-        AppConfig {
-            master_chip: DEFAULT_CHIP.to_string(),
-            log_level: 3,
-            input_binding: HashMap::new(),
-            output_binding: HashMap::new(),
-        }
-    }
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Talk to the running daemon over its control socket
+    Ctl {
+        /// Control socket of the daemon
+        #[structopt(short, long, parse(from_os_str), default_value = DEFAULT_SOCKET)]
+        socket: PathBuf,
+        #[structopt(subcommand)]
+        request: CtlRequest,
+    },
 }
 
-fn poweroff() -> Void {
-    let connection = Connection::new_system()?;
-    let manager = ManagerProxy::new(&connection)?;
-    manager.power_off(false)?;
-    Ok(())
+#[derive(StructOpt, Debug)]
+enum CtlRequest {
+    /// Print the applied config
+    Bindings,
+    /// Print the levels of the held lines
+    Levels,
+    /// Run the action bound to an input or encoder, as if it was used
+    Trigger {
+        /// Input binding, e.g. gpio17, or an encoder by name or pin, e.g. volume
+        input: String,
+        /// short, long or double. Only short presses are bound on encoders
+        #[structopt(short, long, default_value = "short")]
+        gesture: Gesture,
+        /// Turn the encoder one detent instead: clockwise or counter-clockwise
+        #[structopt(short, long)]
+        turn: Option<Direction>,
+    },
+    /// Drive an output binding
+    Set {
+        output: u32,
+        /// on or off
+        #[structopt(parse(try_from_str = parse_level))]
+        value: bool,
+    },
+    /// Print every event as it happens
+    Subscribe,
 }
 
-fn halt() -> Void {
-    let connection = Connection::new_system()?;
-    let manager = ManagerProxy::new(&connection)?;
-    manager.halt(false)?;
-    Ok(())
+impl From<CtlRequest> for Request {
+    fn from(request: CtlRequest) -> Self {
+        match request {
+            CtlRequest::Bindings => Request::Bindings,
+            CtlRequest::Levels => Request::Levels,
+            CtlRequest::Trigger {
+                input,
+                gesture,
+                turn,
+            } => Request::Trigger {
+                input,
+                gesture,
+                turn,
+            },
+            CtlRequest::Set { output, value } => Request::Set { output, value },
+            CtlRequest::Subscribe => Request::Subscribe,
+        }
+    }
 }
 
-fn reboot() -> Void {
-    let connection = Connection::new_system()?;
-    let manager = ManagerProxy::new(&connection)?;
-    manager.reboot(false)?;
-    Ok(())
+fn parse_level(level: &str) -> anyhow::Result<bool> {
+    match level {
+        "on" | "1" | "true" => Ok(true),
+        "off" | "0" | "false" => Ok(false),
+        _ => Err(anyhow!("expected on or off")),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Void {
-    let cfg = sanitise_gpio_names(load_config()?);
-    init_log(&cfg)?;
-
-    info!("{} - {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-    info!("Using {}", cfg.master_chip);
-    let mut chip = Chip::new(cfg.master_chip)?;
-    info!("Setup outputs");
-    for (gpio, func) in cfg.output_binding {
-        exec_binding(&func, &mut chip, gpio.parse::<u32>()?).await?
+    let opt = Opt::from_args();
+    if let Some(Command::Ctl { socket, request }) = opt.cmd {
+        return socket::client(&socket, &request.into()).await;
     }
-    info!("Setup input");
-    let mut input_events: Vec<(AsyncLineEventHandle, String, u32)> = Vec::new();
-    for (gpio, func) in cfg.input_binding {
-        let event = get_evt_handle(&mut chip, gpio.parse::<u32>()?)?;
-        input_events.push((event, func, gpio.parse::<u32>()?));
+    if opt.print_default_config {
+        print!("{}", default_config_toml()?);
+        return Ok(());
     }
-    info!("Start event handler");
-    tick(&mut input_events, &mut chip).await?;
-    Ok(())
-}
-
-fn sanitise_gpio_names(cfg: AppConfig) -> AppConfig {
-    let mut appcfg = cfg.clone();
-    let out_cloned = appcfg.output_binding.clone();
-    appcfg.output_binding.clear();
-    for (k, v) in out_cloned {
-        appcfg
-            .output_binding
-            .insert(k.replace("gpio", "").trim().to_string(), v.clone());
+    if opt.check_config {
+        return check_config(&opt);
     }
 
-    let in_cloned = appcfg.input_binding.clone();
-    appcfg.input_binding.clear();
-    for (k, v) in in_cloned {
-        appcfg
-            .input_binding
-            .insert(k.replace("gpio", "").trim().to_string(), v.clone());
+    let (mut cfg, document) = load_config(&opt.config)?;
+    if let Some(level) = opt.log_level {
+        cfg.log_level = level;
     }
-    appcfg
-}
-
-fn load_config() -> anyhow::Result<AppConfig> {
-    match File::open(CFGPATH) {
-        Ok(mut f) => {
-            let mut buffer = String::default();
-            f.read_to_string(&mut buffer)?;
-            let appcfg: AppConfig = toml::from_str(&buffer)?;
+    init_log(&cfg)?;
 
-            Ok(appcfg)
-        }
-        Err(e) => {
-            warn!("{}", e);
-            let defaultcfg = AppConfig::default();
-            info!("Writing default config to {}", CFGPATH);
-            let toml = toml::to_string_pretty(&defaultcfg)?;
-            fs::write(CFGPATH, toml)?;
-            Ok(defaultcfg)
+    info!("{} - {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    info!("Loaded {}", opt.config.display());
+    info!("Using {}", cfg.master_chip);
+    let chip = CdevBackend::open(&cfg.master_chip)?;
+    let problems = validate(&cfg, &document, Some(chip.num_lines()));
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("{}", problem);
         }
+        bail!("Found {} problem(s) in the config", problems.len());
     }
-}
-
-fn log_level_to_enum(input: u8) -> LevelFilter {
-    match input {
-        0 => LevelFilter::Off,
-        1 => LevelFilter::Error,
-        2 => LevelFilter::Warn,
-        3 => LevelFilter::Info,
-        4 => LevelFilter::Debug,
-        5 => LevelFilter::Trace,
-        _ => LevelFilter::Error,
-    }
-}
 
-fn get_evt_handle(chip: &mut Chip, gpio: u32) -> anyhow::Result<AsyncLineEventHandle> {
-    let handle = chip.get_line(gpio)?;
-    let evt = AsyncLineEventHandle::new(handle.events(
-        LineRequestFlags::INPUT,
-        EventRequestFlags::BOTH_EDGES,
-        &format!("gpio_event_{}", gpio),
-    )?)?;
-    Ok(evt)
+    info!("Power backend {:?}", cfg.power_backend);
+    let ctx = Context::new(Box::new(chip), power::controller(cfg.power_backend));
+    let mut daemon = Daemon::start(cfg, ctx).await?;
+    info!("Start event handler");
+    daemon.tick(&opt.config).await?;
+    Ok(())
 }
 
-async fn exec_binding(input: &str, chip: &mut Chip, gpio: u32) -> Void {
-    match input {
-        "poweroff" | "shutdown" => {
-            debug!("Sync file system...");
-            nix::unistd::sync();
-            thread::sleep(Duration::from_secs(2));
-            warn!("The system will poweroff NOW");
-            poweroff()?;
-        }
-        "restart" => {
-            debug!("Sync file system...");
-            nix::unistd::sync();
-            thread::sleep(Duration::from_secs(2));
-
-            reboot()?;
-        }
-        "halt" => {
-            debug!("Sync file system...");
-            nix::unistd::sync();
-            thread::sleep(Duration::from_secs(2));
-            halt()?;
+/// Validate the config without touching any line. The chip is only opened
+/// to learn its line count, and skipped if it isn't accessible.
+fn check_config(opt: &Opt) -> Void {
+    let (cfg, document) = read_config(&opt.config)?;
+    let num_lines = match CdevBackend::open(&cfg.master_chip) {
+        Ok(chip) => Some(chip.num_lines()),
+        Err(e) => {
+            eprintln!(
+                "Skipping the pin range check, can't open {}: {}",
+                cfg.master_chip, e
+            );
+            None
         }
-        "seton" => static_line(chip, gpio, true).await?,
-        "setoff" => static_line(chip, gpio, false).await?,
-        _ => error!("Unknown function"),
+    };
+    let problems = validate(&cfg, &document, num_lines);
+    for problem in &problems {
+        eprintln!("{}", problem);
     }
-    Ok(())
-}
-
-async fn static_line(chip: &mut Chip, gpionum: u32, state: bool) -> Void {
-    let line = chip.get_line(gpionum)?;
-    info!("Setup GPIO {} output with default", &state);
-    line.request(
-        LineRequestFlags::OUTPUT,
-        u8::from(state),
-        &format!("static_gpio_{}", gpionum),
-    )?;
+    if !problems.is_empty() {
+        bail!(
+            "Found {} problem(s) in {}",
+            problems.len(),
+            opt.config.display()
+        );
+    }
+    println!("{} is valid", opt.config.display());
     Ok(())
 }
 
 fn init_log(cfg: &AppConfig) -> anyhow::Result<()> {
     let loglevel = log_level_to_enum(cfg.log_level);
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            loglevel,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            loglevel,
-            Config::default(),
-            File::create(format!("/var/log/{}.log", env!("CARGO_PKG_NAME"))).unwrap(),
-        ),
-    ])?;
-    Ok(())
-}
-
-async fn tick(
-    events: &mut Vec<(AsyncLineEventHandle, String, u32)>,
-    chip: &mut Chip,
-) -> anyhow::Result<()> {
-    debug!("Event loop started");
-
-    loop {
-        for evt in events.into_iter() {
-            debug!("Checking GPIO {} for events", &evt.2);
-            match evt.0.next().await {
-                Some(event) => {
-                    let info = event?;
-                    if info.event_type() == EventType::FallingEdge {
-                        debug!("Execute {}", &evt.2);
-                        exec_binding(&evt.1, chip, evt.2).await?;
-                    }
-                }
-                None => continue,
-            }
+    let logfile = format!("/var/log/{}.log", env!("CARGO_PKG_NAME"));
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        loglevel,
+        Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )];
+    // Running without root, e.g. on a staging unit, only logs to the terminal.
+    let file_error = match File::create(&logfile) {
+        Ok(file) => {
+            loggers.push(WriteLogger::new(loglevel, Config::default(), file));
+            None
         }
+        Err(e) => Some(e),
+    };
+    CombinedLogger::init(loggers)?;
+    if let Some(e) = file_error {
+        warn!("Not logging to {}: {}", logfile, e);
     }
+    Ok(())
 }
@@ -0,0 +1,188 @@
+//! Prometheus metrics, served as text on `/metrics` by the HTTP server.
+//!
+//! * `radio_edges_total{pin, edge}`: debounced edges on the input lines.
+//! * `radio_actions_total{action}` and `radio_action_failures_total{action}`.
+//! * `radio_action_latency_seconds{action}`: from the edge reaching the
+//!   daemon to the end of the action it completed. Presses completed by a
+//!   timer have no such edge and are left out.
+//! * `radio_output_state{pin}` and `radio_pwm_duty_percent{output}`.
+//! * `radio_config_reloads_total{result}`: `ok`, `rejected` or `incomplete`.
+//! * `radio_uptime_seconds`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use prometheus::{
+    core::Collector, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::{action::Action, gpio::EdgeEvent, input::GestureEvent};
+
+/// Most actions are over within a few milliseconds, commands and players
+/// on the bus may take a while.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Cheap to clone, clones share the same values.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    started: Instant,
+    edges: IntCounterVec,
+    actions: IntCounterVec,
+    failures: IntCounterVec,
+    latency: HistogramVec,
+    outputs: IntGaugeVec,
+    pwm: IntGaugeVec,
+    reloads: IntCounterVec,
+    uptime: Gauge,
+    /// When the last edge of each line reached the daemon.
+    arrivals: Arc<Mutex<HashMap<u32, Instant>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        Metrics {
+            started: Instant::now(),
+            edges: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("radio_edges_total", "Debounced edges on the input lines"),
+                    &["pin", "edge"],
+                ),
+            ),
+            actions: register(
+                &registry,
+                IntCounterVec::new(Opts::new("radio_actions_total", "Actions run"), &["action"]),
+            ),
+            failures: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("radio_action_failures_total", "Actions that failed"),
+                    &["action"],
+                ),
+            ),
+            latency: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "radio_action_latency_seconds",
+                        "Time from an edge to the end of the action it completed",
+                    )
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+                    &["action"],
+                ),
+            ),
+            outputs: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("radio_output_state", "Level of the output lines"),
+                    &["pin"],
+                ),
+            ),
+            pwm: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("radio_pwm_duty_percent", "Duty cycle of the PWM outputs"),
+                    &["output"],
+                ),
+            ),
+            reloads: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("radio_config_reloads_total", "Config reloads"),
+                    &["result"],
+                ),
+            ),
+            uptime: register(
+                &registry,
+                Gauge::new("radio_uptime_seconds", "Time since the daemon started"),
+            ),
+            registry,
+            arrivals: Arc::default(),
+        }
+    }
+}
+
+/// The names are fixed and distinct, so neither creating nor registering
+/// a metric can fail.
+fn register<C>(registry: &Registry, metric: prometheus::Result<C>) -> C
+where
+    C: Collector + Clone + 'static,
+{
+    let metric = metric.unwrap();
+    registry.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+impl Metrics {
+    pub fn edge(&self, event: &EdgeEvent) {
+        self.edges
+            .with_label_values(&[&event.line.to_string(), event.edge.name()])
+            .inc();
+        self.arrivals
+            .lock()
+            .unwrap()
+            .insert(event.line, Instant::now());
+    }
+
+    /// Count a finished action. `event` is the press that fired it.
+    pub fn action(
+        &self,
+        action: &Action,
+        event: Option<&GestureEvent>,
+        result: &anyhow::Result<()>,
+    ) {
+        let name = action.name();
+        self.actions.with_label_values(&[name]).inc();
+        if result.is_err() {
+            self.failures.with_label_values(&[name]).inc();
+        }
+        let arrival = match event {
+            Some(event) if event.edge.is_some() => {
+                self.arrivals.lock().unwrap().get(&event.line).copied()
+            }
+            _ => None,
+        };
+        if let Some(arrival) = arrival {
+            self.latency
+                .with_label_values(&[name])
+                .observe(arrival.elapsed().as_secs_f64());
+        }
+    }
+
+    pub fn reload(&self, result: &str) {
+        self.reloads.with_label_values(&[result]).inc();
+    }
+
+    /// The metrics in the Prometheus text format, with the given line
+    /// states.
+    pub fn render(
+        &self,
+        outputs: &BTreeMap<u32, bool>,
+        pwm: &BTreeMap<String, u8>,
+    ) -> anyhow::Result<String> {
+        // Released lines drop out.
+        self.outputs.reset();
+        for (pin, level) in outputs {
+            self.outputs
+                .with_label_values(&[&pin.to_string()])
+                .set(i64::from(*level));
+        }
+        self.pwm.reset();
+        for (name, duty) in pwm {
+            self.pwm.with_label_values(&[name]).set(i64::from(*duty));
+        }
+        self.uptime.set(self.started.elapsed().as_secs_f64());
+
+        let mut text = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut text)?;
+        Ok(String::from_utf8(text)?)
+    }
+}
@@ -0,0 +1,369 @@
+//! Minimal client for the MPD protocol, enough for the transport and
+//! volume actions a front panel needs.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, bail, Context as _};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+    time,
+};
+
+use crate::{effect::Effect, Void};
+
+/// How long a single request may take before the connection is dropped.
+const IO_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MpdConfig {
+    pub host: String,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl Default for MpdConfig {
+    fn default() -> Self {
+        MpdConfig {
+            host: "localhost".to_string(),
+            port: 6600,
+            password: None,
+        }
+    }
+}
+
+/// What the MPD actions ask of the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MpdCommand {
+    Toggle,
+    Stop,
+    Next,
+    Previous,
+    ChangeVolume(i32),
+    LoadPlaylist(String),
+}
+
+/// An [`MpdClient`] on a task of its own. Commands queue up behind each
+/// other there, so a slow or dead server never holds up the event loop.
+pub struct Mpd {
+    cfg: MpdConfig,
+    commands: mpsc::UnboundedSender<MpdCommand>,
+    _task: Effect,
+}
+
+impl Mpd {
+    pub fn spawn(cfg: MpdConfig) -> Self {
+        let (commands, mut queue) = mpsc::unbounded_channel();
+        let mut client = MpdClient::new(cfg.clone());
+        let task = Effect::spawn(async move {
+            while let Some(command) = queue.recv().await {
+                if let Err(e) = client.run(&command).await {
+                    warn!("MPD {:?} failed: {:#}", command, e);
+                }
+            }
+        });
+        Mpd {
+            cfg,
+            commands,
+            _task: task,
+        }
+    }
+
+    pub fn config(&self) -> &MpdConfig {
+        &self.cfg
+    }
+
+    /// Queue `command`. Failures are only logged, by the client's task.
+    pub fn send(&self, command: MpdCommand) -> Void {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow!("The MPD client has stopped"))
+    }
+}
+
+/// Connects on first use and reconnects whenever the connection broke.
+pub struct MpdClient {
+    cfg: MpdConfig,
+    conn: Option<BufReader<TcpStream>>,
+}
+
+impl MpdClient {
+    pub fn new(cfg: MpdConfig) -> Self {
+        MpdClient { cfg, conn: None }
+    }
+
+    pub fn config(&self) -> &MpdConfig {
+        &self.cfg
+    }
+
+    pub async fn run(&mut self, command: &MpdCommand) -> Void {
+        match command {
+            MpdCommand::Toggle => self.toggle().await,
+            MpdCommand::Stop => self.stop().await,
+            MpdCommand::Next => self.next().await,
+            MpdCommand::Previous => self.previous().await,
+            MpdCommand::ChangeVolume(step) => self.change_volume(*step).await,
+            MpdCommand::LoadPlaylist(name) => self.load_playlist(name).await,
+        }
+    }
+
+    pub async fn toggle(&mut self) -> anyhow::Result<()> {
+        let status = self.status().await?;
+        match status.get("state").map(String::as_str) {
+            Some("play") => self.command("pause 1").await,
+            Some("pause") => self.command("pause 0").await,
+            _ => self.command("play").await,
+        }
+        .map(drop)
+    }
+
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        self.command("stop").await.map(drop)
+    }
+
+    pub async fn next(&mut self) -> anyhow::Result<()> {
+        self.command("next").await.map(drop)
+    }
+
+    pub async fn previous(&mut self) -> anyhow::Result<()> {
+        self.command("previous").await.map(drop)
+    }
+
+    /// Change the volume by `step` percent, clamped to 0..=100.
+    pub async fn change_volume(&mut self, step: i32) -> anyhow::Result<()> {
+        let status = self.status().await?;
+        let volume: i32 = status
+            .get("volume")
+            .ok_or_else(|| anyhow!("MPD did not report a volume"))?
+            .parse()?;
+        if volume < 0 {
+            bail!("MPD has no mixer to change the volume with");
+        }
+        let volume = (volume + step).clamp(0, 100);
+        self.command(&format!("setvol {}", volume)).await.map(drop)
+    }
+
+    /// Replace the queue with a stored playlist and start playing it.
+    pub async fn load_playlist(&mut self, name: &str) -> anyhow::Result<()> {
+        self.command("clear").await?;
+        self.command(&format!("load {}", quote(name))).await?;
+        self.command("play").await.map(drop)
+    }
+
+    pub async fn status(&mut self) -> anyhow::Result<HashMap<String, String>> {
+        Ok(self.command("status").await?.into_iter().collect())
+    }
+
+    /// Send one command, reconnecting and retrying once if the connection
+    /// turned out to be dead.
+    pub async fn command(&mut self, command: &str) -> anyhow::Result<Vec<(String, String)>> {
+        if self.conn.is_some() {
+            match self.request(command).await {
+                Err(e) if e.downcast_ref::<std::io::Error>().is_some() => {
+                    warn!("Lost connection to MPD: {}", e);
+                    self.conn = None;
+                }
+                result => return result,
+            }
+        }
+        self.connect().await?;
+        self.request(command).await
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        let addr = format!("{}:{}", self.cfg.host, self.cfg.port);
+        debug!("Connecting to MPD at {}", addr);
+        let stream = time::timeout(IO_TIMEOUT, TcpStream::connect(&addr))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to MPD at {}", addr))?
+            .with_context(|| format!("Failed to connect to MPD at {}", addr))?;
+        let mut conn = BufReader::new(stream);
+        let mut greeting = String::new();
+        time::timeout(IO_TIMEOUT, conn.read_line(&mut greeting))
+            .await
+            .map_err(|_| anyhow!("MPD at {} did not greet in time", addr))??;
+        if !greeting.starts_with("OK MPD") {
+            bail!("{} is not an MPD server", addr);
+        }
+        info!("Connected to {}", greeting.trim());
+        self.conn = Some(conn);
+        if let Some(password) = self.cfg.password.clone() {
+            self.request(&format!("password {}", quote(&password)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn request(&mut self, command: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let conn = self
+            .conn
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not connected to MPD"))?;
+        let result = time::timeout(IO_TIMEOUT, exchange(conn, command)).await;
+        match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                self.conn = None;
+                Err(e.into())
+            }
+            Err(_) => {
+                self.conn = None;
+                bail!("MPD did not answer `{}` in time", command)
+            }
+        }
+    }
+}
+
+/// Write `command` and read the response up to `OK` or `ACK`. The outer
+/// error is a broken connection, the inner one an error reported by MPD.
+async fn exchange(
+    conn: &mut BufReader<TcpStream>,
+    command: &str,
+) -> std::io::Result<anyhow::Result<Vec<(String, String)>>> {
+    conn.get_mut()
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut pairs = Vec::new();
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line == "OK" {
+            return Ok(Ok(pairs));
+        }
+        if let Some(error) = line.strip_prefix("ACK ") {
+            return Ok(Err(anyhow!("MPD rejected `{}`: {}", command, error)));
+        }
+        if let Some((key, value)) = line.split_once(": ") {
+            pairs.push((key.to_string(), value.to_string()));
+        }
+    }
+}
+
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Commands = Arc<Mutex<Vec<String>>>;
+
+    /// A fake MPD that answers `status` with `status` and anything else
+    /// with `OK`, and hangs up after `per_connection` commands. Returns a
+    /// client for it and the commands it got.
+    async fn server(status: &str, per_connection: usize) -> (MpdClient, Commands) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = Commands::default();
+        let log = commands.clone();
+        let status = status.to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut conn = BufReader::new(stream);
+                conn.get_mut().write_all(b"OK MPD 0.23.5\n").await.unwrap();
+                for _ in 0..per_connection {
+                    let mut line = String::new();
+                    if conn.read_line(&mut line).await.unwrap() == 0 {
+                        break;
+                    }
+                    let command = line.trim_end().to_string();
+                    let answer = match command.as_str() {
+                        "status" => format!("{}OK\n", status),
+                        _ => "OK\n".to_string(),
+                    };
+                    log.lock().unwrap().push(command);
+                    conn.get_mut().write_all(answer.as_bytes()).await.unwrap();
+                }
+            }
+        });
+        (client(port), commands)
+    }
+
+    fn client(port: u16) -> MpdClient {
+        MpdClient::new(MpdConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            password: None,
+        })
+    }
+
+    fn sent(commands: &Commands) -> Vec<String> {
+        commands.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn volume_is_clamped() {
+        let (mut mpd, commands) = server("volume: 98\n", usize::MAX).await;
+        mpd.change_volume(5).await.unwrap();
+        mpd.change_volume(-200).await.unwrap();
+        assert_eq!(
+            sent(&commands),
+            vec!["status", "setvol 100", "status", "setvol 0"]
+        );
+
+        let (mut mpd, commands) = server("volume: -1\n", usize::MAX).await;
+        assert!(mpd.change_volume(5).await.is_err());
+        assert_eq!(sent(&commands), vec!["status"]);
+    }
+
+    #[tokio::test]
+    async fn toggle_follows_the_state() {
+        for (state, expected) in &[("play", "pause 1"), ("pause", "pause 0"), ("stop", "play")] {
+            let status = format!("state: {}\n", state);
+            let (mut mpd, commands) = server(&status, usize::MAX).await;
+            mpd.toggle().await.unwrap();
+            assert_eq!(sent(&commands), vec!["status", expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_eof() {
+        let (mut mpd, commands) = server("", 1).await;
+        mpd.next().await.unwrap();
+        mpd.stop().await.unwrap();
+        mpd.previous().await.unwrap();
+        assert_eq!(sent(&commands), vec!["next", "stop", "previous"]);
+    }
+
+    #[tokio::test]
+    async fn commands_run_in_order_off_the_caller() {
+        let (mpd, commands) = server("volume: 50\n", usize::MAX).await;
+        let port = mpd.config().port;
+        let mpd = Mpd::spawn(mpd.config().clone());
+        assert_eq!(mpd.config().port, port);
+        mpd.send(MpdCommand::ChangeVolume(-10)).unwrap();
+        mpd.send(MpdCommand::Next).unwrap();
+        for _ in 0..100 {
+            if sent(&commands).len() == 3 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(sent(&commands), vec!["status", "setvol 40", "next"]);
+    }
+
+    #[tokio::test]
+    async fn silent_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut mpd = client(listener.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+        let e = mpd.next().await.unwrap_err();
+        assert!(e.to_string().contains("did not greet"), "{:#}", e);
+    }
+}
@@ -0,0 +1,344 @@
+//! Control of media players over MPRIS, for builds that don't run MPD.
+//!
+//! zbus is blocking, so every call runs on the blocking thread pool and the
+//! PropertiesChanged subscription gets a thread of its own.
+
+use std::{
+    collections::HashMap,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use nix::sys::socket::{shutdown, Shutdown};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time};
+use zbus::{fdo::DBusProxy, Connection, Proxy};
+
+use crate::{effect::Effect, Void};
+
+const PLAYER_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// How long the status watcher waits before it tries again after an error.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a call to the player may take before the connection is dropped.
+const CALL_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bus {
+    #[default]
+    Session,
+    System,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+impl PlaybackStatus {
+    fn parse(status: &str) -> anyhow::Result<Self> {
+        match status {
+            "Playing" => Ok(PlaybackStatus::Playing),
+            "Paused" => Ok(PlaybackStatus::Paused),
+            "Stopped" => Ok(PlaybackStatus::Stopped),
+            _ => Err(anyhow!("Unknown playback status {:?}", status)),
+        }
+    }
+}
+
+/// ```toml
+/// [mpris]
+/// bus = "session"
+/// player = "vlc"
+///
+/// [mpris.status_outputs]
+/// gpio5 = "playing"
+/// ```
+///
+/// Players usually sit on the session bus of the user running them. The
+/// daemon runs as a system service, so it only finds that bus with
+/// `DBUS_SESSION_BUS_ADDRESS` set in its environment, e.g.
+/// `unix:path=/run/user/1000/bus`. A player on the system bus needs
+/// `bus = "system"`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct MprisConfig {
+    #[serde(default)]
+    pub bus: Bus,
+    /// Bus name of the player, `vlc` is short for `org.mpris.MediaPlayer2.vlc`.
+    /// Unset uses the first player found on the bus.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub player: Option<String>,
+    /// Output lines that are on while the player is in the given state.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub status_outputs: HashMap<String, PlaybackStatus>,
+}
+
+impl Bus {
+    pub fn connect(self) -> anyhow::Result<Connection> {
+        Ok(match self {
+            Bus::Session => Connection::new_session()?,
+            Bus::System => Connection::new_system()?,
+        })
+    }
+}
+
+/// What the MPRIS actions ask of the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MprisCommand {
+    PlayPause,
+    Next,
+    Previous,
+    Seek(i64),
+    Volume(u8),
+}
+
+/// An [`MprisClient`] on a task of its own. Commands queue up behind each
+/// other there, so a hanging player never holds up the event loop.
+pub struct Mpris {
+    cfg: MprisConfig,
+    commands: mpsc::UnboundedSender<MprisCommand>,
+    _task: Effect,
+}
+
+impl Mpris {
+    pub fn spawn(cfg: MprisConfig) -> Self {
+        let (commands, mut queue) = mpsc::unbounded_channel();
+        let mut client = MprisClient::new(cfg.clone());
+        let task = Effect::spawn(async move {
+            while let Some(command) = queue.recv().await {
+                if let Err(e) = client.run(command).await {
+                    warn!("MPRIS {:?} failed: {:#}", command, e);
+                }
+            }
+        });
+        Mpris {
+            cfg,
+            commands,
+            _task: task,
+        }
+    }
+
+    pub fn config(&self) -> &MprisConfig {
+        &self.cfg
+    }
+
+    /// Queue `command`. Failures are only logged, by the client's task.
+    pub fn send(&self, command: MprisCommand) -> Void {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow!("The MPRIS client has stopped"))
+    }
+}
+
+/// Connects on first use and reconnects after a failed call.
+pub struct MprisClient {
+    cfg: MprisConfig,
+    conn: Option<Connection>,
+}
+
+impl MprisClient {
+    pub fn new(cfg: MprisConfig) -> Self {
+        MprisClient { cfg, conn: None }
+    }
+
+    pub fn config(&self) -> &MprisConfig {
+        &self.cfg
+    }
+
+    pub async fn run(&mut self, command: MprisCommand) -> Void {
+        match command {
+            MprisCommand::PlayPause => self.play_pause().await,
+            MprisCommand::Next => self.next().await,
+            MprisCommand::Previous => self.previous().await,
+            MprisCommand::Seek(offset_ms) => self.seek(offset_ms).await,
+            MprisCommand::Volume(percent) => self.set_volume(percent).await,
+        }
+    }
+
+    pub async fn play_pause(&mut self) -> Void {
+        self.call("PlayPause", ()).await
+    }
+
+    pub async fn next(&mut self) -> Void {
+        self.call("Next", ()).await
+    }
+
+    pub async fn previous(&mut self) -> Void {
+        self.call("Previous", ()).await
+    }
+
+    /// Seek relative to the current position, backwards for a negative offset.
+    pub async fn seek(&mut self, offset_ms: i64) -> Void {
+        self.call("Seek", (offset_ms.saturating_mul(1000),)).await
+    }
+
+    /// Set the volume in percent.
+    pub async fn set_volume(&mut self, percent: u8) -> Void {
+        let volume = f64::from(percent.min(100)) / 100.0;
+        self.with_player(move |player| Ok(player.set_property("Volume", volume)?))
+            .await
+    }
+
+    async fn call<B>(&mut self, method: &'static str, body: B) -> Void
+    where
+        B: Serialize + zvariant::Type + Send + 'static,
+    {
+        debug!("MPRIS {}", method);
+        self.with_player(move |player| Ok(player.call::<_, ()>(method, &body)?))
+            .await
+    }
+
+    async fn with_player<F>(&mut self, f: F) -> Void
+    where
+        F: FnOnce(&Proxy) -> Void + Send + 'static,
+    {
+        let conn = self.conn.take();
+        let bus = self.cfg.bus;
+        let player = self.cfg.player.clone();
+        let call = tokio::task::spawn_blocking(move || {
+            let conn = match conn {
+                Some(conn) => conn,
+                None => bus.connect()?,
+            };
+            let name = find_player(&conn, player.as_deref())?;
+            f(&Proxy::new(&conn, &name, PLAYER_PATH, PLAYER_INTERFACE)?)?;
+            Ok::<_, anyhow::Error>(conn)
+        });
+        // zbus calls can't be cancelled. A hanging one keeps its thread, but
+        // the connection is left to it and the next call starts over.
+        let conn = time::timeout(CALL_TIMEOUT, call)
+            .await
+            .map_err(|_| anyhow!("The MPRIS player did not answer in {:?}", CALL_TIMEOUT))???;
+        // After an error the player may have gone away with the bus
+        // connection, so only a working one is kept for next time.
+        self.conn = Some(conn);
+        Ok(())
+    }
+}
+
+/// The full bus name of the configured player, or the first one on the bus.
+fn find_player(conn: &Connection, player: Option<&str>) -> anyhow::Result<String> {
+    if let Some(player) = player {
+        if player.starts_with(PLAYER_PREFIX) {
+            return Ok(player.to_string());
+        }
+        return Ok(format!("{}{}", PLAYER_PREFIX, player));
+    }
+    DBusProxy::new(conn)?
+        .list_names()?
+        .into_iter()
+        .find(|name| name.starts_with(PLAYER_PREFIX))
+        .ok_or_else(|| anyhow!("No MPRIS player on the bus"))
+}
+
+/// Follows the playback status of the player on a thread of its own.
+///
+/// The thread spends most of its time blocked on the bus socket, so dropping
+/// the watcher shuts the socket down to wake it up and end it.
+pub struct StatusWatcher {
+    rx: mpsc::UnboundedReceiver<PlaybackStatus>,
+    socket: Arc<Mutex<Option<RawFd>>>,
+}
+
+impl StatusWatcher {
+    /// The first status is sent as soon as the player is found, after that
+    /// only changes. `None` once the thread is gone.
+    pub async fn recv(&mut self) -> Option<PlaybackStatus> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for StatusWatcher {
+    fn drop(&mut self) {
+        self.rx.close();
+        if let Some(fd) = *self.socket.lock().unwrap() {
+            if let Err(e) = shutdown(fd, Shutdown::Both) {
+                warn!("Failed to stop the MPRIS status watcher: {}", e);
+            }
+        }
+    }
+}
+
+pub fn watch_status(cfg: MprisConfig) -> StatusWatcher {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let socket = Arc::new(Mutex::new(None));
+    let watched = socket.clone();
+    thread::spawn(move || {
+        while !tx.is_closed() {
+            if let Err(e) = watch(&cfg, &tx, &watched) {
+                if !tx.is_closed() {
+                    warn!("MPRIS status: {:#}", e);
+                    thread::sleep(RETRY_DELAY);
+                }
+            }
+        }
+        debug!("MPRIS status watcher stopped");
+    });
+    StatusWatcher { rx, socket }
+}
+
+/// Publish the socket of a connection in `socket` for as long as it lives.
+struct Published<'a> {
+    socket: &'a Mutex<Option<RawFd>>,
+    conn: Connection,
+}
+
+impl<'a> Published<'a> {
+    fn new(socket: &'a Mutex<Option<RawFd>>, conn: Connection) -> Self {
+        *socket.lock().unwrap() = Some(conn.as_raw_fd());
+        Published { socket, conn }
+    }
+}
+
+impl Drop for Published<'_> {
+    fn drop(&mut self) {
+        // Taken back before the connection closes, so the fd is never shut
+        // down after it has been reused.
+        *self.socket.lock().unwrap() = None;
+    }
+}
+
+fn watch(
+    cfg: &MprisConfig,
+    tx: &mpsc::UnboundedSender<PlaybackStatus>,
+    socket: &Mutex<Option<RawFd>>,
+) -> Void {
+    let published = Published::new(socket, cfg.bus.connect()?);
+    let conn = &published.conn;
+    if tx.is_closed() {
+        return Ok(());
+    }
+    let name = find_player(conn, cfg.player.as_deref())?;
+    let player = Proxy::new(conn, &name, PLAYER_PATH, PLAYER_INTERFACE)?;
+    let properties = Proxy::new(conn, &name, PLAYER_PATH, PROPERTIES_INTERFACE)?;
+    properties.connect_signal("PropertiesChanged", |_| Ok(()))?;
+    info!("Following the playback status of {}", name);
+
+    let mut last = None;
+    loop {
+        let status = PlaybackStatus::parse(&player.get_property::<String>("PlaybackStatus")?)?;
+        if last != Some(status) {
+            debug!("{} is {:?}", name, status);
+            if tx.send(status).is_err() {
+                return Ok(());
+            }
+            last = Some(status);
+        }
+        properties.next_signal()?;
+        if tx.is_closed() {
+            return Ok(());
+        }
+    }
+}
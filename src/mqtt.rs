@@ -0,0 +1,653 @@
+//! Bridge to an MQTT broker, with Home Assistant discovery.
+//!
+//! Topics below the configured `topic`, `radio` by default:
+//!
+//! * `availability`: `online`, or `offline` once the daemon is gone. The
+//!   broker sends the latter as last will, so it also covers crashes.
+//! * `input/<pin>/state`: `ON` or `OFF`, the logical level of an input.
+//! * `input/<pin>/event`: a JSON event for every press.
+//! * `input/<pin>/set`: a gesture name runs the action bound to it.
+//! * `output/<pin>/state`: `ON` or `OFF`.
+//! * `output/<pin>/set`: `ON` or `OFF` drives the output.
+//! * `pwm/<name>/state`: the duty cycle in percent.
+//!
+//! States are retained, and published on every event and every few seconds
+//! in between, when they changed.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use rumqttc::{
+    AsyncClient, ConnectionError, EventLoop, LastWill, MqttOptions, Packet, Publish, QoS,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, watch},
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    config::AppConfig,
+    control::{Control, Event, Request, Response},
+    input::Gesture,
+};
+
+/// How often output states are checked for changes between events.
+const STATE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait before reconnecting to the broker.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Requests the client may queue before the connection takes them. Beyond
+/// that, publishing waits for room.
+const QUEUE: usize = 64;
+
+/// ```toml
+/// [mqtt]
+/// enabled = true
+/// host = "broker.local"
+/// client_id = "kitchen-radio"
+/// topic = "radio/kitchen"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MqttConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Also names the device in Home Assistant.
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Prefix of every topic.
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    #[serde(default = "default_keep_alive_s")]
+    pub keep_alive_s: u64,
+    /// Publish Home Assistant discovery payloads for every binding.
+    #[serde(default = "enabled")]
+    pub discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: default_host(),
+            port: default_port(),
+            client_id: default_client_id(),
+            username: None,
+            password: None,
+            topic: default_topic(),
+            keep_alive_s: default_keep_alive_s(),
+            discovery: true,
+            discovery_prefix: default_discovery_prefix(),
+        }
+    }
+}
+
+impl MqttConfig {
+    fn topic(&self, path: &str) -> String {
+        format!("{}/{}", self.topic, path)
+    }
+
+    fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(self.keep_alive_s));
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+        options.set_last_will(LastWill::new(
+            self.topic("availability"),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        options
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "radio".to_string()
+}
+
+fn default_topic() -> String {
+    "radio".to_string()
+}
+
+fn default_keep_alive_s() -> u64 {
+    30
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+/// The running bridge. Dropping it drops the connection, and the broker
+/// announces the daemon `offline`.
+pub struct Bridge {
+    bindings: watch::Sender<AppConfig>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Bridge {
+    /// Announce the bindings of a new config.
+    pub fn update(&self, cfg: &AppConfig) {
+        // Can't fail, the task holds the receiver until it is aborted.
+        let _ = self.bindings.send(cfg.clone());
+    }
+}
+
+/// Connect to the broker of `cfg.mqtt` and bridge `control` to it.
+pub fn connect(cfg: &AppConfig, control: Control) -> Bridge {
+    let mqtt = cfg.mqtt.clone();
+    info!(
+        "MQTT bridge to {}:{} under {}",
+        mqtt.host, mqtt.port, mqtt.topic
+    );
+    let (bindings, rx) = watch::channel(cfg.clone());
+    let (client, eventloop) = AsyncClient::new(mqtt.options(), QUEUE);
+    let (tx, broker) = mpsc::unbounded_channel();
+    let poller = tokio::spawn(poll(eventloop, tx));
+    let mut link = Link {
+        cfg: mqtt,
+        client,
+        control,
+        connected: false,
+        retained: BTreeMap::new(),
+        discovery: BTreeMap::new(),
+    };
+    let task = tokio::spawn(async move { link.run(broker, rx).await });
+    Bridge {
+        bindings,
+        tasks: vec![poller, task],
+    }
+}
+
+/// What the broker sent that the link acts on, or why the connection broke.
+type Notification = Result<Packet, ConnectionError>;
+
+/// Drive the connection and pass on what the link needs. This runs apart
+/// from the link, so the link may wait for room in the request queue while
+/// the queue is being sent.
+async fn poll(mut eventloop: EventLoop, link: mpsc::UnboundedSender<Notification>) {
+    loop {
+        let notification = match eventloop.poll().await {
+            Ok(rumqttc::Event::Incoming(packet)) => match packet {
+                Packet::ConnAck(_) | Packet::Publish(_) => Ok(packet),
+                _ => continue,
+            },
+            Ok(rumqttc::Event::Outgoing(_)) => continue,
+            Err(e) => Err(e),
+        };
+        let failed = notification.is_err();
+        if link.send(notification).is_err() {
+            return;
+        }
+        if failed {
+            time::sleep(RETRY_DELAY).await;
+        }
+    }
+}
+
+struct Link {
+    cfg: MqttConfig,
+    client: AsyncClient,
+    control: Control,
+    connected: bool,
+    /// Last state queued for each topic, so only changes go out.
+    retained: BTreeMap<String, String>,
+    /// Discovery payloads queued, by topic.
+    discovery: BTreeMap<String, String>,
+}
+
+impl Link {
+    async fn run(
+        &mut self,
+        mut broker: mpsc::UnboundedReceiver<Notification>,
+        mut bindings: watch::Receiver<AppConfig>,
+    ) {
+        let mut events = self.control.subscribe();
+        let mut states = time::interval(STATE_INTERVAL);
+        loop {
+            tokio::select! {
+                notification = broker.recv() => match notification {
+                    Some(Ok(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker {}", self.cfg.host);
+                        self.connected = true;
+                        let cfg = bindings.borrow().clone();
+                        self.on_connect(&cfg).await;
+                    }
+                    Some(Ok(Packet::Publish(publish))) => self.command(&publish).await,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        if self.connected {
+                            warn!("Lost the MQTT broker: {}", e);
+                        } else {
+                            debug!("MQTT broker unreachable: {}", e);
+                        }
+                        self.connected = false;
+                    }
+                    None => return,
+                },
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        self.publish_event(&event).await;
+                        // Only a press runs an action that may have changed
+                        // a level, the tick catches up with the edges.
+                        if let Event::Press { .. } = event {
+                            self.publish_states().await;
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => warn!("MQTT bridge missed {} events", missed),
+                    Err(RecvError::Closed) => return,
+                },
+                _ = states.tick() => self.publish_states().await,
+                changed = bindings.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    let cfg = bindings.borrow().clone();
+                    self.announce(&cfg).await;
+                    self.publish_states().await;
+                }
+            }
+        }
+    }
+
+    /// A fresh session has no subscriptions, and the broker may have been
+    /// restarted without its retained messages.
+    async fn on_connect(&mut self, cfg: &AppConfig) {
+        for path in &["input/+/set", "output/+/set"] {
+            if let Err(e) = self
+                .client
+                .subscribe(self.cfg.topic(path), QoS::AtLeastOnce)
+                .await
+            {
+                warn!("MQTT subscribe failed: {}", e);
+            }
+        }
+        self.publish(self.cfg.topic("availability"), "online".to_string(), true)
+            .await;
+        self.retained.clear();
+        self.discovery.clear();
+        self.announce(cfg).await;
+        self.publish_states().await;
+    }
+
+    async fn command(&mut self, publish: &Publish) {
+        let payload = String::from_utf8_lossy(&publish.payload);
+        debug!("MQTT {} = {}", publish.topic, payload);
+        let result = self.parse_command(&publish.topic, payload.trim());
+        let response = match result {
+            Ok(request) => self.control.request(request).await,
+            Err(e) => Response::Error {
+                message: format!("{:#}", e),
+            },
+        };
+        if let Response::Error { message } = response {
+            warn!("MQTT {}: {}", publish.topic, message);
+        }
+        self.publish_states().await;
+    }
+
+    fn parse_command(&self, topic: &str, payload: &str) -> anyhow::Result<Request> {
+        let path = topic
+            .strip_prefix(&self.cfg.topic)
+            .and_then(|path| path.strip_prefix('/'))
+            .ok_or_else(|| anyhow!("Unexpected topic"))?;
+        let parts: Vec<&str> = path.split('/').collect();
+        match parts.as_slice() {
+            ["input", pin, "set"] => Ok(Request::Trigger {
+                input: pin.to_string(),
+                gesture: payload.parse()?,
+                turn: None,
+            }),
+            ["output", pin, "set"] => Ok(Request::Set {
+                output: pin.parse()?,
+                value: parse_switch(payload)?,
+            }),
+            _ => Err(anyhow!("Unexpected topic")),
+        }
+    }
+
+    async fn publish_event(&mut self, event: &Event) {
+        match event {
+            Event::Edge { .. } => {}
+            Event::Press { pin, .. } => match serde_json::to_string(event) {
+                Ok(payload) => {
+                    let topic = self.cfg.topic(&format!("input/{}/event", pin));
+                    self.publish(topic, payload, false).await;
+                }
+                Err(e) => warn!("Failed to serialise {:?}: {}", event, e),
+            },
+        }
+    }
+
+    /// Publish the levels that changed since they were last published.
+    async fn publish_states(&mut self) {
+        if !self.connected {
+            return;
+        }
+        let (inputs, outputs, pwm) = match self.control.request(Request::Levels).await {
+            Response::Levels {
+                inputs,
+                outputs,
+                pwm,
+            } => (inputs, outputs, pwm),
+            _ => return,
+        };
+        let states = inputs
+            .into_iter()
+            .map(|(pin, level)| (format!("input/{}/state", pin), switch(level)))
+            .chain(
+                outputs
+                    .into_iter()
+                    .map(|(pin, level)| (format!("output/{}/state", pin), switch(level))),
+            )
+            .chain(
+                pwm.into_iter()
+                    .map(|(name, duty)| (format!("pwm/{}/state", name), duty.to_string())),
+            );
+        for (path, payload) in states {
+            let topic = self.cfg.topic(&path);
+            if self.retained.get(&topic) != Some(&payload)
+                && self.publish(topic.clone(), payload.clone(), true).await
+            {
+                self.retained.insert(topic, payload);
+            }
+        }
+    }
+
+    /// Publish the discovery payloads of `cfg`, and clear those of bindings
+    /// that are gone.
+    async fn announce(&mut self, cfg: &AppConfig) {
+        if !self.connected {
+            return;
+        }
+        let discovery = match self.cfg.discovery {
+            true => discovery(&self.cfg, cfg),
+            false => BTreeMap::new(),
+        };
+        let stale: Vec<String> = self
+            .discovery
+            .keys()
+            .filter(|topic| !discovery.contains_key(*topic))
+            .cloned()
+            .collect();
+        for topic in stale {
+            if self.publish(topic.clone(), String::new(), true).await {
+                self.discovery.remove(&topic);
+            }
+        }
+        for (topic, payload) in discovery {
+            if self.discovery.get(&topic) != Some(&payload)
+                && self.publish(topic.clone(), payload.clone(), true).await
+            {
+                self.discovery.insert(topic, payload);
+            }
+        }
+    }
+
+    /// Queue a publish, waiting for room in the queue. False if it couldn't
+    /// be queued, so it isn't taken for sent.
+    async fn publish(&self, topic: String, payload: String, retain: bool) -> bool {
+        match self
+            .client
+            .publish(&topic, QoS::AtLeastOnce, retain, payload)
+            .await
+        {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("MQTT publish to {} failed: {}", topic, e);
+                false
+            }
+        }
+    }
+}
+
+fn switch(level: bool) -> String {
+    match level {
+        true => "ON".to_string(),
+        false => "OFF".to_string(),
+    }
+}
+
+fn parse_switch(payload: &str) -> anyhow::Result<bool> {
+    match payload {
+        "ON" => Ok(true),
+        "OFF" => Ok(false),
+        _ => Err(anyhow!("Expected ON or OFF, not {:?}", payload)),
+    }
+}
+
+/// Home Assistant discovery payloads for every binding, by topic.
+///
+/// Inputs become a binary sensor, plus a device trigger and a button for
+/// each bound gesture. Outputs become switches and PWM outputs sensors.
+fn discovery(mqtt: &MqttConfig, cfg: &AppConfig) -> BTreeMap<String, String> {
+    let device = json!({
+        "identifiers": [mqtt.client_id],
+        "name": mqtt.client_id,
+        "model": "radIO",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let mut payloads = BTreeMap::new();
+    let mut entity = |component: &str, object: &str, mut payload: Value| {
+        let topic = format!(
+            "{}/{}/{}/{}/config",
+            mqtt.discovery_prefix, component, mqtt.client_id, object
+        );
+        payload["device"] = device.clone();
+        // Device triggers are not entities, they have neither.
+        if component != "device_automation" {
+            payload["unique_id"] = json!(format!("{}_{}", mqtt.client_id, object));
+            payload["availability_topic"] = json!(mqtt.topic("availability"));
+        }
+        payloads.insert(topic, payload.to_string());
+    };
+
+    for (key, binding) in &cfg.input_binding {
+        let object = format!("gpio{}", key);
+        entity(
+            "binary_sensor",
+            &object,
+            json!({
+                "name": format!("GPIO {}", key),
+                "state_topic": mqtt.topic(&format!("input/{}/state", key)),
+            }),
+        );
+        let gestures = [Gesture::Short, Gesture::Long, Gesture::Double];
+        for gesture in gestures
+            .iter()
+            .filter(|g| binding.action_for(**g).is_some())
+        {
+            let object = format!("{}_{}", object, gesture.name());
+            entity(
+                "device_automation",
+                &object,
+                json!({
+                    "automation_type": "trigger",
+                    "topic": mqtt.topic(&format!("input/{}/event", key)),
+                    "value_template": "{{ value_json.gesture }}",
+                    "payload": gesture.name(),
+                    "type": format!("button_{}_press", gesture.name()),
+                    "subtype": format!("gpio{}", key),
+                }),
+            );
+            entity(
+                "button",
+                &object,
+                json!({
+                    "name": format!("GPIO {} {} press", key, gesture.name()),
+                    "command_topic": mqtt.topic(&format!("input/{}/set", key)),
+                    "payload_press": gesture.name(),
+                }),
+            );
+        }
+    }
+    for key in cfg.output_binding.keys() {
+        entity(
+            "switch",
+            &format!("gpio{}", key),
+            json!({
+                "name": format!("GPIO {}", key),
+                "state_topic": mqtt.topic(&format!("output/{}/state", key)),
+                "command_topic": mqtt.topic(&format!("output/{}/set", key)),
+            }),
+        );
+    }
+    for name in cfg.pwm_output.keys() {
+        entity(
+            "sensor",
+            &format!("pwm_{}", name),
+            json!({
+                "name": format!("PWM {}", name),
+                "state_topic": mqtt.topic(&format!("pwm/{}/state", name)),
+                "unit_of_measurement": "%",
+            }),
+        );
+    }
+    payloads
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, PingResp, PubAck, SubAck, SubscribeReasonCode};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::control::Monitor;
+
+    const INPUTS: u32 = 30;
+
+    /// A panel with more retained topics than fit in the request queue.
+    fn panel(port: u16) -> AppConfig {
+        let mut text = format!(
+            "master_chip = \"sim\"\nlog_level = 3\n\
+             [mqtt]\nenabled = true\nhost = \"127.0.0.1\"\nport = {}\n\
+             [output_binding]\n[input_binding]\n",
+            port
+        );
+        for pin in 0..INPUTS {
+            text += &format!(
+                "gpio{} = {{ action = \"none\", long_press = \"none\", double_press = \"none\" }}\n",
+                pin
+            );
+        }
+        toml::from_str(&text).unwrap()
+    }
+
+    /// Answer the level requests of the bridge until it is gone.
+    fn daemon() -> Control {
+        let (tx, mut requests) = mpsc::unbounded_channel::<(Request, crate::control::Reply)>();
+        tokio::spawn(async move {
+            while let Some((_, reply)) = requests.recv().await {
+                let _ = reply.send(Response::Levels {
+                    inputs: (0..INPUTS).map(|pin| (pin, true)).collect(),
+                    outputs: BTreeMap::new(),
+                    pwm: BTreeMap::new(),
+                });
+            }
+        });
+        Control::new(tx, Monitor::default())
+    }
+
+    /// Play the broker for one session and collect the retained topics
+    /// published, until nothing new comes for a while.
+    async fn broker(mut conn: TcpStream) -> BTreeSet<String> {
+        let mut topics = BTreeSet::new();
+        let mut incoming = BytesMut::new();
+        let mut answer = BytesMut::new();
+        loop {
+            let packet = match rumqttc::read(&mut incoming, 1 << 20) {
+                Ok(packet) => packet,
+                Err(_) => {
+                    let read =
+                        time::timeout(Duration::from_millis(500), conn.read_buf(&mut incoming));
+                    match read.await {
+                        Ok(Ok(n)) if n > 0 => continue,
+                        _ => return topics,
+                    }
+                }
+            };
+            match packet {
+                Packet::Connect(_) => ConnAck::new(ConnectReturnCode::Success, false)
+                    .write(&mut answer)
+                    .unwrap(),
+                Packet::Subscribe(subscribe) => SubAck::new(
+                    subscribe.pkid,
+                    vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)],
+                )
+                .write(&mut answer)
+                .unwrap(),
+                Packet::Publish(publish) => {
+                    if publish.retain {
+                        topics.insert(publish.topic);
+                    }
+                    PubAck::new(publish.pkid).write(&mut answer).unwrap()
+                }
+                Packet::PingReq => PingResp.write(&mut answer).unwrap(),
+                _ => 0,
+            };
+            conn.write_all(&answer).await.unwrap();
+            answer.clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_large_panel_is_announced_in_full() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = panel(listener.local_addr().unwrap().port());
+        let expected = discovery(&cfg.mqtt, &cfg).len() + INPUTS as usize + 1;
+        assert!(expected > QUEUE);
+
+        let _bridge = connect(&cfg, daemon());
+        let (conn, _) = listener.accept().await.unwrap();
+        let topics = broker(conn).await;
+
+        for topic in discovery(&cfg.mqtt, &cfg).keys() {
+            assert!(topics.contains(topic), "{} is missing", topic);
+        }
+        for pin in 0..INPUTS {
+            let topic = cfg.mqtt.topic(&format!("input/{}/state", pin));
+            assert!(topics.contains(&topic), "{} is missing", topic);
+        }
+        assert!(topics.contains(&cfg.mqtt.topic("availability")));
+        assert_eq!(topics.len(), expected);
+    }
+}
@@ -0,0 +1,74 @@
+//! The systemd notification protocol, spoken directly over `$NOTIFY_SOCKET`.
+//!
+//! With `Type=notify` systemd waits for `READY=1` before it counts the
+//! daemon as started, and with `WatchdogSec=` it restarts the daemon once
+//! the event loop stops sending `WATCHDOG=1`. Outside such a unit there is
+//! no socket and every notification is dropped.
+
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    process,
+    time::Duration,
+};
+
+use log::warn;
+
+use crate::{config::AppConfig, Void};
+
+/// Send `state`, one `KEY=value` assignment per line.
+pub fn notify(state: &str) {
+    if let Err(e) = send(state) {
+        warn!("Failed to notify systemd: {:#}", e);
+    }
+}
+
+fn send(state: &str) -> Void {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    // A leading `@` names a socket in the abstract namespace.
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1`, half the timeout systemd asked for.
+/// `None` if the unit has no watchdog, or it watches another process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match usec {
+        0 => None,
+        usec => Some(Duration::from_micros(usec) / 2),
+    }
+}
+
+/// `STATUS=` line for `systemctl status`, the bindings of `cfg`.
+pub fn status(cfg: &AppConfig) -> String {
+    format!(
+        "STATUS={}, {}, {}, {}",
+        count(cfg.input_binding.len(), "input"),
+        count(cfg.output_binding.len(), "output"),
+        count(cfg.encoder.len(), "encoder"),
+        count(cfg.pwm_output.len(), "PWM output"),
+    )
+}
+
+fn count(n: usize, what: &str) -> String {
+    match n {
+        1 => format!("1 {}", what),
+        n => format!("{} {}s", n, what),
+    }
+}
@@ -0,0 +1,118 @@
+//! System power control.
+//!
+//! Bindings never talk to logind directly; they go through a
+//! [`PowerController`] picked by `power_backend` in the config, so a dev
+//! machine can run the daemon with `dry-run` without being shut down.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use logind_zbus::ManagerProxy;
+use serde::{Deserialize, Serialize};
+use tokio::{task, time};
+use zbus::Connection;
+
+use crate::Void;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    PowerOff,
+    Halt,
+    Reboot,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerBackend {
+    #[default]
+    Logind,
+    DryRun,
+}
+
+pub trait PowerController: Send {
+    fn execute(&mut self, action: PowerAction) -> Void;
+}
+
+/// Time the disks get after the sync before the power goes.
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// Asks systemd-logind over the system bus to change the power state.
+///
+/// The sync, the wait and the bus calls block, so they run on a task of
+/// their own and the event loop carries on meanwhile. Failures are only
+/// logged.
+pub struct Logind;
+
+impl PowerController for Logind {
+    fn execute(&mut self, action: PowerAction) -> Void {
+        tokio::spawn(async move {
+            if let Err(e) = logind(action).await {
+                error!("{:?} failed: {:#}", action, e);
+            }
+        });
+        Ok(())
+    }
+}
+
+async fn logind(action: PowerAction) -> Void {
+    debug!("Sync file system...");
+    task::spawn_blocking(nix::unistd::sync).await?;
+    time::sleep(SETTLE).await;
+
+    task::spawn_blocking(move || {
+        let connection = Connection::new_system()?;
+        let manager = ManagerProxy::new(&connection)?;
+        match action {
+            PowerAction::PowerOff => {
+                warn!("The system will poweroff NOW");
+                manager.power_off(false)?;
+            }
+            PowerAction::Halt => manager.halt(false)?,
+            PowerAction::Reboot => manager.reboot(false)?,
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Only logs what would have happened.
+pub struct DryRun;
+
+impl PowerController for DryRun {
+    fn execute(&mut self, action: PowerAction) -> Void {
+        info!("Dry run: skipping {:?}", action);
+        Ok(())
+    }
+}
+
+/// Records every call. Clones share the same log, so a test can hand one
+/// to [`Context::new`](crate::dispatch::Context::new) and read the calls
+/// back from another.
+#[derive(Clone, Default)]
+pub struct MockPower {
+    calls: Arc<Mutex<Vec<PowerAction>>>,
+}
+
+impl MockPower {
+    pub fn calls(&self) -> Vec<PowerAction> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl PowerController for MockPower {
+    fn execute(&mut self, action: PowerAction) -> Void {
+        debug!("Mock power controller received {:?}", action);
+        self.calls.lock().unwrap().push(action);
+        Ok(())
+    }
+}
+
+pub fn controller(backend: PowerBackend) -> Box<dyn PowerController> {
+    match backend {
+        PowerBackend::Logind => Box::new(Logind),
+        PowerBackend::DryRun => Box::new(DryRun),
+    }
+}
@@ -0,0 +1,334 @@
+//! Hardware PWM channels through the sysfs PWM class.
+//!
+//! A channel lives at `<root>/pwmchipN/pwmM` once it has been exported.
+//! The root is `/sys/class/pwm` on a real system and can point at any
+//! directory tree with the same layout for tests.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use log::{debug, info, warn};
+use tokio::time::{self, Instant};
+
+use crate::{effect::Effect, Void};
+
+pub const DEFAULT_PWM_ROOT: &str = "/sys/class/pwm";
+
+/// How long to wait for udev to make a freshly exported channel usable.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(1);
+const EXPORT_POLL: Duration = Duration::from_millis(10);
+/// Interval between duty cycle updates of a fade.
+const FADE_TICK: Duration = Duration::from_millis(20);
+
+struct State {
+    period_ns: u64,
+    duty_ns: u64,
+}
+
+/// An exported PWM channel.
+pub struct PwmChannel {
+    chip_dir: PathBuf,
+    channel: u32,
+    dir: PathBuf,
+    state: Mutex<State>,
+}
+
+impl PwmChannel {
+    /// Export `channel` of `pwmchip<chip>` if needed, program the period and
+    /// duty cycle and enable it.
+    pub async fn open(
+        root: &Path,
+        chip: u32,
+        channel: u32,
+        period_ns: u64,
+        duty: u8,
+    ) -> anyhow::Result<Self> {
+        let chip_dir = root.join(format!("pwmchip{}", chip));
+        let dir = chip_dir.join(format!("pwm{}", channel));
+        if !dir.exists() {
+            info!("Export PWM channel {}", dir.display());
+            write(&chip_dir.join("export"), channel)?;
+            let deadline = Instant::now() + EXPORT_TIMEOUT;
+            // The enable attribute is the last one udev hands over.
+            while !dir.join("enable").exists() {
+                if Instant::now() > deadline {
+                    anyhow::bail!("{} did not show up after the export", dir.display());
+                }
+                time::sleep(EXPORT_POLL).await;
+            }
+        }
+
+        let state = State {
+            period_ns: read(&dir.join("period")).unwrap_or(0),
+            duty_ns: read(&dir.join("duty_cycle")).unwrap_or(0),
+        };
+        let pwm = PwmChannel {
+            chip_dir,
+            channel,
+            dir,
+            state: Mutex::new(state),
+        };
+        pwm.set(Some(period_ns), duty)?;
+        write(&pwm.dir.join("enable"), 1)?;
+        Ok(pwm)
+    }
+
+    /// Set the duty cycle in percent, and the period if given. The duty
+    /// cycle keeps its share of the period when only the period changes.
+    pub fn set(&self, period_ns: Option<u64>, duty: u8) -> Void {
+        let mut state = self.state.lock().unwrap();
+        let period_ns = period_ns.unwrap_or(state.period_ns);
+        let duty_ns = period_ns * u64::from(duty.min(100)) / 100;
+        // The kernel rejects a duty cycle longer than the period at any point.
+        if period_ns >= state.period_ns {
+            self.write_period(&mut state, period_ns)?;
+            self.write_duty(&mut state, duty_ns)
+        } else {
+            self.write_duty(&mut state, duty_ns)?;
+            self.write_period(&mut state, period_ns)
+        }
+    }
+
+    /// Current duty cycle in percent.
+    pub fn duty(&self) -> u8 {
+        let state = self.state.lock().unwrap();
+        match state.period_ns {
+            0 => 0,
+            period => (state.duty_ns * 100 / period) as u8,
+        }
+    }
+
+    /// Disable and unexport the channel.
+    pub fn release(&self) -> Void {
+        info!("Release PWM channel {}", self.dir.display());
+        write(&self.dir.join("enable"), 0)?;
+        write(&self.chip_dir.join("unexport"), self.channel)
+    }
+
+    fn write_period(&self, state: &mut State, period_ns: u64) -> Void {
+        if state.period_ns != period_ns {
+            write(&self.dir.join("period"), period_ns)?;
+            state.period_ns = period_ns;
+        }
+        Ok(())
+    }
+
+    fn write_duty(&self, state: &mut State, duty_ns: u64) -> Void {
+        if state.duty_ns != duty_ns {
+            write(&self.dir.join("duty_cycle"), duty_ns)?;
+            state.duty_ns = duty_ns;
+        }
+        Ok(())
+    }
+
+    /// Set the duty cycle in ns, for fades that need more than percent steps.
+    fn set_duty_ns(&self, duty_ns: u64) -> Void {
+        let mut state = self.state.lock().unwrap();
+        let duty_ns = duty_ns.min(state.period_ns);
+        self.write_duty(&mut state, duty_ns)
+    }
+
+    fn duty_ns(&self) -> u64 {
+        self.state.lock().unwrap().duty_ns
+    }
+
+    fn period_ns(&self) -> u64 {
+        self.state.lock().unwrap().period_ns
+    }
+}
+
+/// Move the duty cycle of `pwm` to `duty` percent over `duration`. The fade
+/// stops when the returned handle is dropped or the channel goes away.
+pub fn fade(name: &str, pwm: &Arc<PwmChannel>, duty: u8, duration: Duration) -> Effect {
+    debug!("Fade PWM {} to {}% over {:?}", name, duty, duration);
+    let name = name.to_string();
+    let pwm: Weak<PwmChannel> = Arc::downgrade(pwm);
+    Effect::spawn(async move {
+        let (from, to) = match pwm.upgrade() {
+            Some(pwm) => (
+                pwm.duty_ns(),
+                pwm.period_ns() * u64::from(duty.min(100)) / 100,
+            ),
+            None => return,
+        };
+        let start = Instant::now();
+        let mut at = start;
+        loop {
+            let progress = match duration.as_nanos() {
+                0 => 1.0,
+                total => ((at - start).as_nanos() as f64 / total as f64).min(1.0),
+            };
+            let duty_ns = from as f64 + (to as f64 - from as f64) * progress;
+            // Only hold the channel while writing, so it can be released
+            // while the fade sleeps.
+            let result = match pwm.upgrade() {
+                Some(pwm) => pwm.set_duty_ns(duty_ns.round() as u64),
+                None => return,
+            };
+            if let Err(e) = result {
+                warn!("PWM {}: fade failed: {:#}", name, e);
+                return;
+            }
+            if progress >= 1.0 {
+                return;
+            }
+            at += FADE_TICK;
+            time::sleep_until(at).await;
+        }
+    })
+}
+
+fn write(path: &Path, value: impl ToString) -> Void {
+    fs::write(path, value.to_string())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn read(path: &Path) -> anyhow::Result<u64> {
+    Ok(fs::read_to_string(path)?.trim().parse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sysfs PWM tree with a single chip under the temp dir.
+    struct Tree {
+        root: PathBuf,
+        chip: PathBuf,
+    }
+
+    impl Tree {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("rad_io-pwm-{}-{}", name, std::process::id()));
+            let chip = root.join("pwmchip0");
+            fs::create_dir_all(&chip).unwrap();
+            fs::write(chip.join("export"), "").unwrap();
+            fs::write(chip.join("unexport"), "").unwrap();
+            Tree { root, chip }
+        }
+
+        /// Create `pwm<channel>` the way the kernel does on an export.
+        fn export(&self, channel: u32, period_ns: u64, duty_ns: u64) {
+            let dir = self.channel(channel);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("period"), period_ns.to_string()).unwrap();
+            fs::write(dir.join("duty_cycle"), duty_ns.to_string()).unwrap();
+            fs::write(dir.join("enable"), "0").unwrap();
+        }
+
+        fn channel(&self, channel: u32) -> PathBuf {
+            self.chip.join(format!("pwm{}", channel))
+        }
+
+        fn read(&self, path: &str) -> String {
+            fs::read_to_string(self.chip.join(path)).unwrap()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[tokio::test]
+    async fn open_exports_programs_and_enables() {
+        let tree = Tree::new("open");
+        let udev = {
+            let export = tree.chip.join("export");
+            let dir = tree.channel(1);
+            tokio::spawn(async move {
+                while fs::read_to_string(&export).unwrap() != "1" {
+                    time::sleep(EXPORT_POLL).await;
+                }
+                // Like udev, the attributes show up one after the other.
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join("period"), "0").unwrap();
+                fs::write(dir.join("duty_cycle"), "0").unwrap();
+                time::sleep(EXPORT_POLL * 3).await;
+                fs::write(dir.join("enable"), "0").unwrap();
+            })
+        };
+
+        let pwm = PwmChannel::open(&tree.root, 0, 1, 1_000_000, 25)
+            .await
+            .unwrap();
+        udev.await.unwrap();
+        assert_eq!(tree.read("pwm1/period"), "1000000");
+        assert_eq!(tree.read("pwm1/duty_cycle"), "250000");
+        assert_eq!(tree.read("pwm1/enable"), "1");
+        assert_eq!(pwm.duty(), 25);
+    }
+
+    #[tokio::test]
+    async fn open_gives_up_on_a_channel_that_never_shows_up() {
+        let tree = Tree::new("missing");
+        let result = PwmChannel::open(&tree.root, 0, 2, 1_000_000, 25).await;
+        assert!(result.is_err());
+        assert_eq!(tree.read("export"), "2");
+    }
+
+    /// Open channel `n` at 1 ms and 50%, with a period that can't be written
+    /// any more. That stops `set` half way and shows whether the duty cycle
+    /// went first.
+    async fn stuck_period(tree: &Tree, n: u32) -> PwmChannel {
+        tree.export(n, 1_000_000, 500_000);
+        let pwm = PwmChannel::open(&tree.root, 0, n, 1_000_000, 50)
+            .await
+            .unwrap();
+        let period = tree.channel(n).join("period");
+        fs::remove_file(&period).unwrap();
+        fs::create_dir(&period).unwrap();
+        pwm
+    }
+
+    #[tokio::test]
+    async fn duty_cycle_never_exceeds_the_period() {
+        let tree = Tree::new("order");
+
+        let shrink = stuck_period(&tree, 0).await;
+        assert!(shrink.set(Some(500_000), 50).is_err());
+        assert_eq!(tree.read("pwm0/duty_cycle"), "250000");
+
+        let grow = stuck_period(&tree, 1).await;
+        assert!(grow.set(Some(2_000_000), 50).is_err());
+        assert_eq!(tree.read("pwm1/duty_cycle"), "500000");
+    }
+
+    #[tokio::test]
+    async fn set_keeps_the_share_of_a_new_period() {
+        let tree = Tree::new("share");
+        tree.export(0, 0, 0);
+        let pwm = PwmChannel::open(&tree.root, 0, 0, 1_000_000, 40)
+            .await
+            .unwrap();
+
+        pwm.set(Some(2_000_000), 40).unwrap();
+        assert_eq!(tree.read("pwm0/period"), "2000000");
+        assert_eq!(tree.read("pwm0/duty_cycle"), "800000");
+        pwm.set(None, 150).unwrap();
+        assert_eq!(tree.read("pwm0/duty_cycle"), "2000000");
+        assert_eq!(pwm.duty(), 100);
+    }
+
+    #[tokio::test]
+    async fn release_disables_and_unexports() {
+        let tree = Tree::new("release");
+        tree.export(3, 1_000_000, 0);
+        let pwm = PwmChannel::open(&tree.root, 0, 3, 1_000_000, 60)
+            .await
+            .unwrap();
+
+        pwm.release().unwrap();
+        assert_eq!(tree.read("pwm3/enable"), "0");
+        assert_eq!(tree.read("unexport"), "3");
+        // An existing channel isn't exported again.
+        assert_eq!(tree.read("export"), "");
+    }
+}
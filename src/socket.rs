@@ -0,0 +1,214 @@
+//! Unix domain control socket.
+//!
+//! Clients send one JSON [`Request`] per line and get one JSON [`Response`]
+//! per line back. After a `subscribe` request the connection carries an
+//! `event` response for everything that happens on the lines, until the
+//! client hangs up.
+
+use std::{
+    fs::{self, Permissions},
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use log::{debug, info, warn};
+use nix::unistd::{chown, Group};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::broadcast::error::RecvError,
+    task::JoinHandle,
+};
+
+use crate::{
+    control::{Control, Request, Response},
+    Void,
+};
+
+pub const DEFAULT_SOCKET: &str = "/run/radio.sock";
+
+/// ```toml
+/// [control]
+/// socket = "/run/radio.sock"
+/// mode = "0660"
+/// group = "audio"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ControlConfig {
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_socket")]
+    pub socket: PathBuf,
+    /// Octal file mode of the socket. Connecting needs write permission.
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    /// Group to hand the socket to, so its members can connect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            enabled: true,
+            socket: default_socket(),
+            mode: default_mode(),
+            group: None,
+        }
+    }
+}
+
+impl ControlConfig {
+    pub fn parse_mode(&self) -> anyhow::Result<u32> {
+        let mode = u32::from_str_radix(&self.mode, 8)
+            .map_err(|_| anyhow!("`{}` is not an octal file mode", self.mode))?;
+        if mode > 0o777 {
+            bail!("`{}` is not an octal file mode", self.mode);
+        }
+        Ok(mode)
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn default_socket() -> PathBuf {
+    PathBuf::from(DEFAULT_SOCKET)
+}
+
+fn default_mode() -> String {
+    "0660".to_string()
+}
+
+/// The listening socket. Dropping it stops accepting connections and
+/// removes the socket file.
+pub struct Server {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Err(e) = fs::remove_file(&self.path) {
+            debug!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Bind the socket and serve `control` on it.
+pub fn listen(cfg: &ControlConfig, control: Control) -> anyhow::Result<Server> {
+    let path = cfg.socket.clone();
+    // A socket left behind by a crash would make the bind fail.
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+        _ => {}
+    }
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    fs::set_permissions(&path, Permissions::from_mode(cfg.parse_mode()?))?;
+    if let Some(name) = &cfg.group {
+        let group = Group::from_name(name)?.ok_or_else(|| anyhow!("No group named {}", name))?;
+        chown(&path, None, Some(group.gid))?;
+    }
+    info!("Control socket at {}", path.display());
+
+    let task = tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, control).await {
+                            debug!("Control connection closed: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Control socket: {}", e),
+            }
+        }
+    });
+    Ok(Server { path, task })
+}
+
+async fn serve(stream: UnixStream, control: Control) -> Void {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let message = format!("Invalid request: {}", e);
+                send(&mut write, &Response::Error { message }).await?;
+                continue;
+            }
+        };
+        debug!("Control request {:?}", request);
+        if request == Request::Subscribe {
+            return stream_events(&mut write, &control).await;
+        }
+        send(&mut write, &control.request(request).await).await?;
+    }
+    Ok(())
+}
+
+async fn stream_events<W: AsyncWrite + Unpin>(write: &mut W, control: &Control) -> Void {
+    let mut events = control.subscribe();
+    send(write, &Response::Ok).await?;
+    loop {
+        match events.recv().await {
+            Ok(event) => send(write, &Response::Event(event)).await?,
+            Err(RecvError::Lagged(missed)) => {
+                let message = format!("Missed {} events", missed);
+                send(write, &Response::Error { message }).await?
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(write: &mut W, response: &Response) -> Void {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// Send `request` to the daemon at `path` and print what comes back, one
+/// JSON document per line. Returns once the daemon has answered, or the
+/// connection ends for a subscription.
+pub async fn client(path: &Path, request: &Request) -> Void {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Failed to connect to {}", path.display()))?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(read).lines();
+    let response = match lines.next_line().await? {
+        Some(response) => response,
+        None => bail!("The daemon closed the connection"),
+    };
+    println!("{}", response);
+    if serde_json::from_str::<Value>(&response)?["result"] == "error" {
+        bail!("The daemon reported an error");
+    }
+    if *request == Request::Subscribe {
+        while let Some(line) = lines.next_line().await? {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,677 @@
+//! Config checks that run before any GPIO line is requested.
+//!
+//! Deserialising only proves the config is well formed. This pass catches
+//! everything that would otherwise blow up half way through the hardware
+//! setup, and reports every problem at once with the key it was found at.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, iter,
+};
+
+use crate::{
+    action::Action,
+    config::{pin_number, AppConfig},
+    effect::Pattern,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// TOML key path, e.g. `input_binding.gpio17.long_press`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn push(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Problem {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+}
+
+/// What actions can drive.
+struct Outputs<'a> {
+    gpio: HashSet<u32>,
+    pwm: HashSet<&'a str>,
+}
+
+/// Check `cfg`, parsed from `document`, against a chip with `num_lines`
+/// lines. Pass `None` to skip the range check, e.g. when no chip is
+/// available.
+pub fn validate(cfg: &AppConfig, document: &toml::Value, num_lines: Option<u32>) -> Vec<Problem> {
+    let mut problems = Problems::default();
+
+    match toml::Value::try_from(cfg) {
+        Ok(read) => check_keys(&mut problems, "", document, &read),
+        Err(e) => problems.push("", format!("can't be checked for unknown keys: {}", e)),
+    }
+
+    if cfg.log_level > 5 {
+        problems.push(
+            "log_level",
+            format!("{} is not between 0 and 5", cfg.log_level),
+        );
+    }
+
+    if let Err(e) = cfg.control.parse_mode() {
+        problems.push("control.mode", e.to_string());
+    }
+    if cfg.mqtt.topic.is_empty() || cfg.mqtt.topic.contains(['+', '#']) {
+        problems.push(
+            "mqtt.topic",
+            format!("`{}` can't be used as a topic prefix", cfg.mqtt.topic),
+        );
+    }
+    if cfg.http.port == 0 {
+        problems.push("http.port", "must be greater than 0");
+    }
+    if cfg.watchdog.interval_s == 0 {
+        problems.push("watchdog.interval_s", "must be greater than 0");
+    }
+    if cfg.mqtt.password.is_some() && cfg.mqtt.username.is_none() {
+        problems.push("mqtt.password", "needs a username");
+    }
+    if !is_bus_name(&cfg.dbus.name) {
+        problems.push(
+            "dbus.name",
+            format!("`{}` is not a well-known bus name", cfg.dbus.name),
+        );
+    }
+
+    let outputs = Outputs {
+        gpio: cfg
+            .output_binding
+            .keys()
+            .filter_map(|key| pin_number(key).ok())
+            .collect(),
+        pwm: cfg.pwm_output.keys().map(String::as_str).collect(),
+    };
+
+    // Pin -> first key that claimed it, so duplicates point back at it.
+    let mut claimed: HashMap<u32, String> = HashMap::new();
+    let mut claim = |problems: &mut Problems, path: String, key: &str| {
+        let pin = match pin_number(key) {
+            Ok(pin) => pin,
+            Err(_) => {
+                problems.push(path, format!("`{}` is not a GPIO number", key));
+                return;
+            }
+        };
+        if let Some(lines) = num_lines {
+            if pin >= lines {
+                problems.push(
+                    path.clone(),
+                    format!("GPIO {} is out of range, the chip has {} lines", pin, lines),
+                );
+            }
+        }
+        match claimed.get(&pin) {
+            Some(first) => {
+                problems.push(path, format!("GPIO {} is already bound by {}", pin, first))
+            }
+            None => {
+                claimed.insert(pin, path);
+            }
+        }
+    };
+
+    for (key, binding) in sorted(&cfg.input_binding) {
+        let path = format!("input_binding.{}", key);
+        claim(&mut problems, path.clone(), key);
+
+        check_input_action(&mut problems, &path, &binding.action, &outputs);
+        if let Some(action) = &binding.long_press {
+            let path = format!("{}.long_press", path);
+            check_input_action(&mut problems, &path, action, &outputs);
+        }
+        if let Some(action) = &binding.double_press {
+            let path = format!("{}.double_press", path);
+            check_input_action(&mut problems, &path, action, &outputs);
+        }
+
+        let gestures = binding.long_press.is_some() || binding.double_press.is_some();
+        if binding.edge.is_some() && gestures {
+            problems.push(
+                format!("{}.edge", path),
+                "has no effect once long_press or double_press is bound",
+            );
+        }
+        if binding.hold_ms.is_some() && binding.long_press.is_none() {
+            problems.push(format!("{}.hold_ms", path), "is set but long_press is not");
+        }
+        if binding.double_ms.is_some() && binding.double_press.is_none() {
+            problems.push(
+                format!("{}.double_ms", path),
+                "is set but double_press is not",
+            );
+        }
+        let timing = binding.gesture_config();
+        if let (Some(hold), Some(double)) = (timing.long, timing.double) {
+            if hold <= double {
+                problems.push(
+                    format!("{}.hold_ms", path),
+                    "has to be longer than the double press window",
+                );
+            }
+        }
+    }
+
+    for (key, binding) in sorted(&cfg.output_binding) {
+        let path = format!("output_binding.{}", key);
+        claim(&mut problems, path.clone(), key);
+
+        check_action(&mut problems, &path, &binding.action, &outputs);
+        match binding.action {
+            Action::SetOn | Action::SetOff => {}
+            Action::Effect { output: None, .. } => {}
+            Action::Effect {
+                output: Some(pin), ..
+            } => {
+                if pin_number(key) != Ok(pin) {
+                    problems.push(
+                        format!("{}.output", path),
+                        "an output binding can only run effects on its own line",
+                    );
+                }
+            }
+            _ => problems.push(
+                format!("{}.action", path),
+                "outputs can only be set with `seton`, `setoff` or `effect`",
+            ),
+        }
+    }
+
+    for (name, binding) in sorted(&cfg.encoder) {
+        let path = format!("encoder.{}", name);
+        let pins = iter::once(("pin_a", binding.pin_a))
+            .chain(iter::once(("pin_b", binding.pin_b)))
+            .chain(binding.switch_pin.map(|pin| ("switch_pin", pin)));
+        for (field, pin) in pins {
+            claim(
+                &mut problems,
+                format!("{}.{}", path, field),
+                &pin.to_string(),
+            );
+        }
+
+        if ![1, 2, 4].contains(&binding.steps_per_detent) {
+            problems.push(
+                format!("{}.steps_per_detent", path),
+                format!("{} is not 1, 2 or 4", binding.steps_per_detent),
+            );
+        }
+        if binding.accel_max == 0 {
+            problems.push(format!("{}.accel_max", path), "must be greater than 0");
+        }
+        let actions = iter::once(("clockwise", &binding.clockwise))
+            .chain(iter::once((
+                "counter_clockwise",
+                &binding.counter_clockwise,
+            )))
+            .chain(binding.press.as_ref().map(|action| ("press", action)));
+        for (field, action) in actions {
+            let path = format!("{}.{}", path, field);
+            check_input_action(&mut problems, &path, action, &outputs);
+        }
+        if binding.switch_pin.is_none() && binding.press.is_some() {
+            problems.push(format!("{}.press", path), "is set but switch_pin is not");
+        }
+    }
+
+    let mut channels: HashMap<(u32, u32), String> = HashMap::new();
+    for (name, binding) in sorted(&cfg.pwm_output) {
+        let path = format!("pwm_output.{}", name);
+        if binding.period_ns == 0 {
+            problems.push(format!("{}.period_ns", path), "must be greater than 0");
+        }
+        for (field, duty) in
+            iter::once(("duty", Some(binding.duty))).chain(iter::once(("on_exit", binding.on_exit)))
+        {
+            if let Some(duty) = duty {
+                check_duty(&mut problems, &format!("{}.{}", path, field), duty);
+            }
+        }
+        match channels.get(&(binding.chip, binding.channel)) {
+            Some(first) => problems.push(
+                path,
+                format!(
+                    "pwmchip{}/pwm{} is already used by {}",
+                    binding.chip, binding.channel, first
+                ),
+            ),
+            None => {
+                channels.insert((binding.chip, binding.channel), path);
+            }
+        }
+    }
+
+    for key in sorted(&cfg.mpris.status_outputs).keys() {
+        let path = format!("mpris.status_outputs.{}", key);
+        match pin_number(key) {
+            Ok(pin) if outputs.gpio.contains(&pin) => {}
+            Ok(pin) => problems.push(path, format!("GPIO {} is not an output binding", pin)),
+            Err(_) => problems.push(path, format!("`{}` is not a GPIO number", key)),
+        }
+    }
+
+    problems.0
+}
+
+/// Report the keys of `document` missing from `read`, the config written
+/// back. Serde skips unknown keys silently, and a binding's keys are shared
+/// with its flattened action, so a misspelt `debounce_ms` would otherwise
+/// go unnoticed.
+fn check_keys(problems: &mut Problems, path: &str, document: &toml::Value, read: &toml::Value) {
+    let (document, read) = match (document, read) {
+        (toml::Value::Table(document), toml::Value::Table(read)) => (document, read),
+        _ => return,
+    };
+    for (key, value) in document {
+        let path = match path {
+            "" => key.clone(),
+            path => format!("{}.{}", path, key),
+        };
+        match read.get(key) {
+            Some(read) => check_keys(problems, &path, value, read),
+            // Empty maps are left out when written back.
+            None if is_empty(value) => {}
+            None => problems.push(path, "is not a known key"),
+        }
+    }
+}
+
+fn is_empty(value: &toml::Value) -> bool {
+    match value {
+        toml::Value::Table(table) => table.is_empty(),
+        toml::Value::Array(array) => array.is_empty(),
+        _ => false,
+    }
+}
+
+/// Check an action bound to an input, which has no line it could drive.
+fn check_input_action(problems: &mut Problems, path: &str, action: &Action, outputs: &Outputs) {
+    check_action(problems, path, action, outputs);
+    match action {
+        Action::SetOn | Action::SetOff => {
+            problems.push(path, "an input line can't be driven as an output")
+        }
+        Action::Effect { output: None, .. } => problems.push(
+            format!("{}.output", path),
+            "is needed to run an effect from an input",
+        ),
+        _ => {}
+    }
+}
+
+fn check_action(problems: &mut Problems, path: &str, action: &Action, outputs: &Outputs) {
+    match action {
+        Action::Set { output } | Action::Clear { output } | Action::Toggle { output } => {
+            check_gpio_output(problems, path, *output, outputs)
+        }
+        Action::Pulse {
+            output,
+            duration_ms,
+        } => {
+            check_gpio_output(problems, path, *output, outputs);
+            if *duration_ms == 0 {
+                problems.push(format!("{}.duration_ms", path), "must be greater than 0");
+            }
+        }
+        Action::Effect { output, pattern } => {
+            if let Some(pin) = output {
+                check_gpio_output(problems, path, *pin, outputs);
+            }
+            check_pattern(problems, path, pattern);
+        }
+        Action::Command(spec) => {
+            if spec.cmd.trim().is_empty() {
+                problems.push(format!("{}.cmd", path), "must not be empty");
+            }
+            if let Some(cwd) = &spec.cwd {
+                if !cwd.is_dir() {
+                    problems.push(
+                        format!("{}.cwd", path),
+                        format!("{} is not a directory", cwd.display()),
+                    );
+                }
+            }
+            if spec.timeout_ms == Some(0) {
+                problems.push(format!("{}.timeout_ms", path), "must be greater than 0");
+            }
+        }
+        Action::PwmSet {
+            output,
+            duty,
+            period_ns,
+        } => {
+            check_pwm_output(problems, path, output, outputs);
+            check_duty(problems, &format!("{}.duty", path), *duty);
+            if *period_ns == Some(0) {
+                problems.push(format!("{}.period_ns", path), "must be greater than 0");
+            }
+        }
+        Action::PwmFade { output, duty, .. } => {
+            check_pwm_output(problems, path, output, outputs);
+            check_duty(problems, &format!("{}.duty", path), *duty);
+        }
+        Action::MprisVolume { volume } if *volume > 100 => problems.push(
+            format!("{}.volume", path),
+            format!("{} is not between 0 and 100", volume),
+        ),
+        _ => {}
+    }
+}
+
+fn check_gpio_output(problems: &mut Problems, path: &str, pin: u32, outputs: &Outputs) {
+    if !outputs.gpio.contains(&pin) {
+        problems.push(
+            format!("{}.output", path),
+            format!("GPIO {} is not an output binding", pin),
+        );
+    }
+}
+
+fn check_pwm_output(problems: &mut Problems, path: &str, output: &str, outputs: &Outputs) {
+    if !outputs.pwm.contains(output) {
+        problems.push(
+            format!("{}.output", path),
+            format!("there is no pwm_output named `{}`", output),
+        );
+    }
+}
+
+fn check_duty(problems: &mut Problems, path: &str, duty: u8) {
+    if duty > 100 {
+        problems.push(path, format!("{} is not between 0 and 100", duty));
+    }
+}
+
+fn check_pattern(problems: &mut Problems, path: &str, pattern: &Pattern) {
+    match *pattern {
+        Pattern::Blink {
+            on_ms: 0,
+            off_ms: 0,
+        } => problems.push(path, "on_ms and off_ms can't both be 0"),
+        Pattern::Breathe { period_ms: 0, .. } => {
+            problems.push(format!("{}.period_ms", path), "must be greater than 0")
+        }
+        Pattern::Breathe { pwm_hz, .. } if pwm_hz == 0 || pwm_hz > 1000 => problems.push(
+            format!("{}.pwm_hz", path),
+            format!("{} is not between 1 and 1000", pwm_hz),
+        ),
+        _ => {}
+    }
+}
+
+fn sorted<T>(bindings: &HashMap<String, T>) -> BTreeMap<&String, &T> {
+    bindings.iter().collect()
+}
+
+/// Two or more dot separated elements of `[A-Za-z0-9_-]`, none starting
+/// with a digit.
+fn is_bus_name(name: &str) -> bool {
+    name.len() <= 255
+        && name.split('.').count() >= 2
+        && name.split('.').all(|element| {
+            !element.is_empty()
+                && !element.starts_with(|c: char| c.is_ascii_digit())
+                && element
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Paths of the unknown keys in `text`.
+    fn unknown_keys(text: &str) -> Vec<String> {
+        let cfg: AppConfig = toml::from_str(text).unwrap();
+        let document = toml::from_str(text).unwrap();
+        validate(&cfg, &document, None)
+            .into_iter()
+            .filter(|problem| problem.message == "is not a known key")
+            .map(|problem| problem.path)
+            .collect()
+    }
+
+    /// Everything wrong with the bindings in `text`, as it would be reported.
+    fn problems(bindings: &str, num_lines: Option<u32>) -> Vec<String> {
+        let text = format!("master_chip = \"sim\"\nlog_level = 3\n{}", bindings);
+        let cfg: AppConfig = toml::from_str(&text).unwrap();
+        let document = toml::from_str(&text).unwrap();
+        validate(&cfg, &document, num_lines)
+            .iter()
+            .map(Problem::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn documented_keys_are_known() {
+        let text = r#"
+            master_chip = "/dev/gpiochip0"
+            log_level = 3
+            debounce_ms = 20
+            power_backend = "dry-run"
+
+            [input_binding]
+            gpio17 = "shutdown"
+            gpio27 = { action = "restart", debounce_ms = 80, edge = "both" }
+            gpio22 = { action = "command", cmd = "/usr/bin/mpc", args = ["toggle"], cwd = "/", env = {}, long_press = "poweroff", hold_ms = 900 }
+            gpio23 = { action = "none", double_press = { action = "command", cmd = "mpc", env = { PLAYER = "mpd" }, timeout_ms = 5000 }, active_low = false, bias = "pull-down" }
+            gpio24 = { action = "effect", output = 4, pattern = "flash", count = 3, on_ms = 50 }
+
+            [output_binding]
+            gpio4 = { action = "seton", on_exit = false }
+            gpio6 = { action = "effect", pattern = "breathe", period_ms = 4000, pwm_hz = 200 }
+
+            [encoder.volume]
+            pin_a = 5
+            pin_b = 7
+            switch_pin = 13
+            accel_ms = 60
+            clockwise = { action = "mpd-volume-up", step = 2 }
+            counter_clockwise = "mpd-volume-down"
+            press = "mpd-toggle"
+
+            [pwm_output.backlight]
+            chip = 0
+            channel = 1
+            period_ns = 1000000
+            duty = 80
+
+            [mpd]
+            host = "localhost"
+            port = 6600
+            password = "secret"
+
+            [mpris]
+            bus = "session"
+            player = "vlc"
+
+            [mpris.status_outputs]
+            gpio4 = "playing"
+
+            [mqtt]
+            enabled = true
+            username = "radio"
+            password = "secret"
+
+            [watchdog]
+            device = "/dev/watchdog1"
+        "#;
+        assert_eq!(unknown_keys(text), Vec::<String>::new());
+    }
+
+    #[test]
+    fn misspelt_keys_are_reported() {
+        let text = r#"
+            master_chip = "/dev/gpiochip0"
+            log_level = 3
+            debounse_ms = 20
+
+            [input_binding]
+            gpio17 = { action = "restart", debounce = 80 }
+            gpio22 = { action = "none", long_press = { action = "toggle", output = 4, outptu = 5 }, hold_sm = 900 }
+
+            [output_binding]
+            gpio4 = { action = "setoff", bais = "pull-up" }
+
+            [encoder.volume]
+            pin_a = 5
+            pin_b = 6
+            clockwise = "mpd-next"
+            counter_clockwise = "mpd-previous"
+            acel_ms = 60
+
+            [http]
+            prot = 80
+        "#;
+        assert_eq!(
+            unknown_keys(text),
+            vec![
+                "debounse_ms",
+                "encoder.volume.acel_ms",
+                "http.prot",
+                "input_binding.gpio17.debounce",
+                "input_binding.gpio22.hold_sm",
+                "input_binding.gpio22.long_press.outptu",
+                "output_binding.gpio4.bais",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_pins_point_back_at_the_first_binding() {
+        let text = r#"
+            [input_binding]
+            5 = "restart"
+            gpio5 = "poweroff"
+
+            [output_binding]
+        "#;
+        assert_eq!(
+            problems(text, None),
+            vec!["input_binding.gpio5: GPIO 5 is already bound by input_binding.5"]
+        );
+    }
+
+    #[test]
+    fn pins_beyond_the_chip_are_out_of_range() {
+        let text = r#"
+            [input_binding]
+            gpio40 = "restart"
+
+            [output_binding]
+
+            [encoder.volume]
+            pin_a = 5
+            pin_b = 28
+            clockwise = "mpd-next"
+            counter_clockwise = "mpd-previous"
+        "#;
+        assert_eq!(
+            problems(text, Some(28)),
+            vec![
+                "input_binding.gpio40: GPIO 40 is out of range, the chip has 28 lines",
+                "encoder.volume.pin_b: GPIO 28 is out of range, the chip has 28 lines",
+            ]
+        );
+        assert_eq!(problems(text, None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn a_line_is_used_once() {
+        let text = r#"
+            [input_binding]
+            gpio5 = "restart"
+
+            [output_binding]
+            gpio5 = "setoff"
+
+            [encoder.volume]
+            pin_a = 6
+            pin_b = 7
+            switch_pin = 5
+            clockwise = "mpd-next"
+            counter_clockwise = "mpd-previous"
+
+            [pwm_output.backlight]
+            chip = 0
+            channel = 1
+            period_ns = 1000000
+
+            [pwm_output.buzzer]
+            chip = 0
+            channel = 1
+            period_ns = 500000
+        "#;
+        assert_eq!(
+            problems(text, None),
+            vec![
+                "output_binding.gpio5: GPIO 5 is already bound by input_binding.gpio5",
+                "encoder.volume.switch_pin: GPIO 5 is already bound by input_binding.gpio5",
+                "pwm_output.buzzer: pwmchip0/pwm1 is already used by pwm_output.backlight",
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns_have_to_be_runnable() {
+        let text = r#"
+            [input_binding]
+
+            [output_binding]
+            gpio4 = { action = "effect", pattern = "blink", on_ms = 0, off_ms = 0 }
+            gpio5 = { action = "effect", pattern = "breathe", period_ms = 0 }
+            gpio6 = { action = "effect", pattern = "breathe", pwm_hz = 2000 }
+        "#;
+        assert_eq!(
+            problems(text, None),
+            vec![
+                "output_binding.gpio4: on_ms and off_ms can't both be 0",
+                "output_binding.gpio5.period_ms: must be greater than 0",
+                "output_binding.gpio6.pwm_hz: 2000 is not between 1 and 1000",
+            ]
+        );
+    }
+
+    #[test]
+    fn duty_cycles_are_percentages() {
+        let text = r#"
+            [input_binding]
+            gpio17 = { action = "pwm-set", output = "backlight", duty = 150 }
+            gpio27 = { action = "pwm-fade", output = "backlight", duty = 101, duration_ms = 500 }
+
+            [output_binding]
+
+            [pwm_output.backlight]
+            chip = 0
+            channel = 1
+            period_ns = 1000000
+            duty = 120
+            on_exit = 200
+        "#;
+        assert_eq!(
+            problems(text, None),
+            vec![
+                "input_binding.gpio17.duty: 150 is not between 0 and 100",
+                "input_binding.gpio27.duty: 101 is not between 0 and 100",
+                "pwm_output.backlight.duty: 120 is not between 0 and 100",
+                "pwm_output.backlight.on_exit: 200 is not between 0 and 100",
+            ]
+        );
+    }
+}
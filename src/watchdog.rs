@@ -0,0 +1,228 @@
+//! A hardware watchdog, fed by the event loop, so a wedged daemon gets the
+//! machine rebooted.
+//!
+//! The device is closed with the magic `V` on a clean shutdown, which stops
+//! the timer. If the daemon dies or hangs the timer runs out instead.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _};
+use log::{debug, info, warn};
+use nix::libc::{self, c_int};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Interval};
+
+use crate::Void;
+
+nix::ioctl_read!(get_boot_status, b'W', 2, c_int);
+nix::ioctl_read!(get_timeout, b'W', 7, c_int);
+
+/// `WDIOF_CARDRESET` in the boot status: the last reboot was the
+/// watchdog's.
+const CARD_RESET: c_int = 0x0020;
+
+/// ```toml
+/// [watchdog]
+/// enabled = true
+/// device = "/dev/watchdog"
+/// interval_s = 5
+/// ```
+///
+/// The interval has to stay well below the device's timeout, 15 seconds on
+/// a Raspberry Pi.
+///
+/// For testing, `device` can be a plain file or a FIFO that another process
+/// already reads from. A FIFO without a reader is refused rather than waited
+/// on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_device")]
+    pub device: PathBuf,
+    /// Seconds between two feeds.
+    #[serde(default = "default_interval")]
+    pub interval_s: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            enabled: false,
+            device: default_device(),
+            interval_s: default_interval(),
+        }
+    }
+}
+
+fn default_device() -> PathBuf {
+    PathBuf::from("/dev/watchdog")
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+/// The open device. Opening it starts the timer, dropping it without
+/// [`Watchdog::close`] leaves the timer running.
+pub struct Watchdog {
+    device: File,
+    path: PathBuf,
+    feeds: Interval,
+}
+
+impl Watchdog {
+    pub fn open(cfg: &WatchdogConfig) -> anyhow::Result<Self> {
+        // Without O_NONBLOCK opening a FIFO would block the event loop
+        // until something reads it.
+        let device = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&cfg.device)
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::ENXIO) => anyhow!("Nothing reads from {}", cfg.device.display()),
+                _ => anyhow::Error::new(e)
+                    .context(format!("Failed to open {}", cfg.device.display())),
+            })?;
+        let interval = Duration::from_secs(cfg.interval_s);
+        describe(&device, &cfg.device, interval);
+        Ok(Watchdog {
+            device,
+            path: cfg.device.clone(),
+            feeds: time::interval(interval),
+        })
+    }
+
+    /// Wait for the next feed and write it.
+    pub async fn feed(&mut self) -> Void {
+        self.feeds.tick().await;
+        self.device
+            .write_all(b"\0")
+            .and_then(|_| self.device.flush())
+            .with_context(|| format!("Failed to feed {}", self.path.display()))
+    }
+
+    /// Stop the timer with the magic close.
+    pub fn close(mut self) {
+        match self.device.write_all(b"V") {
+            Ok(()) => info!("Stopped the watchdog {}", self.path.display()),
+            Err(e) => warn!(
+                "Failed to stop the watchdog {}, it will reboot the machine: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Log the device's timeout and why the machine last booted. Devices that
+/// don't answer, like a plain file standing in for one, are fed regardless.
+fn describe(device: &File, path: &Path, interval: Duration) {
+    let fd = device.as_raw_fd();
+    let mut timeout: c_int = 0;
+    match unsafe { get_timeout(fd, &mut timeout) } {
+        Ok(_) => {
+            info!(
+                "Watchdog {} times out after {}s, feeding it every {:?}",
+                path.display(),
+                timeout,
+                interval
+            );
+            if interval >= Duration::from_secs(timeout as u64) {
+                warn!(
+                    "Feeding the watchdog every {:?} is too slow for its {}s timeout",
+                    interval, timeout
+                );
+            }
+        }
+        Err(e) => debug!("Watchdog {} has no timeout: {}", path.display(), e),
+    }
+    let mut status: c_int = 0;
+    match unsafe { get_boot_status(fd, &mut status) } {
+        Ok(_) if status & CARD_RESET != 0 => {
+            warn!(
+                "The watchdog rebooted the machine (boot status {:#x})",
+                status
+            )
+        }
+        Ok(_) => info!("Watchdog boot status {:#x}", status),
+        Err(e) => debug!("Watchdog {} has no boot status: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read, time::Instant};
+
+    use nix::{sys::stat::Mode, unistd::mkfifo};
+
+    use super::*;
+
+    fn device(name: &str) -> WatchdogConfig {
+        WatchdogConfig {
+            enabled: true,
+            device: std::env::temp_dir().join(format!(
+                "rad_io-watchdog-{}-{}",
+                name,
+                std::process::id()
+            )),
+            interval_s: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn feeds_on_the_interval_then_closes_with_v() {
+        let cfg = device("file");
+        fs::write(&cfg.device, "").unwrap();
+        let mut watchdog = Watchdog::open(&cfg).unwrap();
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            watchdog.feed().await.unwrap();
+        }
+        // The first feed goes out right away.
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(3),
+            "{:?}",
+            elapsed
+        );
+        assert_eq!(fs::read(&cfg.device).unwrap(), b"\0\0\0");
+
+        watchdog.close();
+        assert_eq!(fs::read(&cfg.device).unwrap(), b"\0\0\0V");
+        fs::remove_file(&cfg.device).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_fifo_is_only_opened_with_a_reader() {
+        let cfg = device("fifo");
+        mkfifo(&cfg.device, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        match Watchdog::open(&cfg) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                format!("Nothing reads from {}", cfg.device.display())
+            ),
+            Ok(_) => panic!("{} opened without a reader", cfg.device.display()),
+        }
+
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&cfg.device)
+            .unwrap();
+        let mut watchdog = Watchdog::open(&cfg).unwrap();
+        watchdog.feed().await.unwrap();
+        watchdog.close();
+        let mut written = Vec::new();
+        reader.read_to_end(&mut written).unwrap();
+        assert_eq!(written, b"\0V");
+        fs::remove_file(&cfg.device).unwrap();
+    }
+}
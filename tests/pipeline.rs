@@ -0,0 +1,215 @@
+//! The whole pipeline on the simulated chip: config, line setup, edge
+//! events, gestures and dispatch, down to the output levels and the power
+//! calls.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use nix::sys::signal::{raise, Signal};
+use rad_io::{
+    config::AppConfig,
+    control::{Request, Response},
+    daemon::Daemon,
+    dispatch::Context,
+    encoder::Direction,
+    gpio::{Edge, SimBackend},
+    input::Gesture,
+    power::{MockPower, PowerAction},
+};
+
+const CONFIG: &str = r#"
+master_chip = "sim"
+log_level = 3
+
+[control]
+enabled = false
+
+[mpd]
+host = "localhost"
+port = 6600
+password = "hunter2"
+
+[mqtt]
+username = "radio"
+password = "hunter2"
+
+[input_binding]
+gpio5 = { action = "toggle", output = 6 }
+gpio7 = { action = "none", long_press = "poweroff", hold_ms = 100 }
+
+[output_binding]
+gpio6 = "setoff"
+
+[encoder.volume]
+pin_a = 8
+pin_b = 9
+clockwise = { action = "set", output = 6 }
+counter_clockwise = { action = "clear", output = 6 }
+
+[pwm_output.backlight]
+chip = 0
+channel = 0
+period_ns = 1000000
+duty = 80
+on_exit = 10
+"#;
+
+/// `CONFIG` with its PWM channel in a sysfs tree of its own under the temp
+/// dir. The channel is already exported, nothing has to wait for udev.
+fn config(name: &str) -> (String, PathBuf) {
+    let pwm_root = std::env::temp_dir().join(format!("rad_io-{}-{}", name, std::process::id()));
+    let channel = pwm_root.join("pwmchip0/pwm0");
+    fs::create_dir_all(&channel).unwrap();
+    for attribute in &["period", "duty_cycle", "enable"] {
+        fs::write(channel.join(attribute), "0").unwrap();
+    }
+    (format!("pwm_root = {:?}\n{}", pwm_root, CONFIG), pwm_root)
+}
+
+/// Poll `check` until it holds, for up to a second.
+async fn eventually(check: impl Fn() -> bool) -> bool {
+    for _ in 0..100 {
+        if check() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+/// Push the button on `line` down and let it go again after `held_ms`.
+/// Inputs are active low, so going down is an electrical falling edge.
+async fn press(sim: &SimBackend, line: u32, timestamp: u64, held_ms: u64) {
+    sim.inject(line, Edge::Falling, timestamp).unwrap();
+    tokio::time::sleep(Duration::from_millis(held_ms)).await;
+    sim.inject(line, Edge::Rising, timestamp + held_ms * 1_000_000)
+        .unwrap();
+}
+
+#[tokio::test]
+async fn presses_drive_outputs_and_power() {
+    let (text, pwm_root) = config("pipeline");
+    let cfg: AppConfig = toml::from_str(&text).unwrap();
+    let channel = pwm_root.join("pwmchip0/pwm0");
+    let sim = SimBackend::new(16);
+    let power = MockPower::default();
+    let ctx = Context::new(Box::new(sim.clone()), Box::new(power.clone()));
+    let mut daemon = Daemon::start(cfg, ctx).await.unwrap();
+    let control = daemon.control();
+
+    assert_eq!(sim.requested(), [5, 6, 7, 8, 9].iter().copied().collect());
+    assert_eq!(sim.output(6), Some(false));
+    let duty_cycle = || fs::read_to_string(channel.join("duty_cycle")).unwrap();
+    assert_eq!(duty_cycle(), "800000");
+
+    let event_loop =
+        tokio::spawn(async move { daemon.tick(Path::new("/nonexistent")).await.unwrap() });
+
+    press(&sim, 5, 1_000_000_000, 20).await;
+    assert!(eventually(|| sim.output(6) == Some(true)).await);
+    press(&sim, 5, 2_000_000_000, 20).await;
+    assert!(eventually(|| sim.output(6) == Some(false)).await);
+
+    // Too short for the long press, and the short press does nothing.
+    press(&sim, 7, 3_000_000_000, 20).await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(power.calls().is_empty());
+    press(&sim, 7, 4_000_000_000, 150).await;
+    assert!(eventually(|| power.calls() == vec![PowerAction::PowerOff]).await);
+
+    let response = control
+        .request(Request::Set {
+            output: 6,
+            value: true,
+        })
+        .await;
+    assert!(matches!(response, Response::Ok), "{:?}", response);
+    assert_eq!(sim.output(6), Some(true));
+    let response = control
+        .request(Request::Set {
+            output: 5,
+            value: true,
+        })
+        .await;
+    assert!(matches!(response, Response::Error { .. }), "{:?}", response);
+
+    // Encoders are triggered by name, or by a pin, with a turn.
+    let response = control
+        .request(Request::Trigger {
+            input: "volume".to_string(),
+            gesture: Gesture::Short,
+            turn: Some(Direction::CounterClockwise),
+        })
+        .await;
+    assert!(matches!(response, Response::Ok), "{:?}", response);
+    assert_eq!(sim.output(6), Some(false));
+    let response = control
+        .request(Request::Trigger {
+            input: "gpio9".to_string(),
+            gesture: Gesture::Short,
+            turn: Some(Direction::Clockwise),
+        })
+        .await;
+    assert!(matches!(response, Response::Ok), "{:?}", response);
+    assert_eq!(sim.output(6), Some(true));
+    let response = control
+        .request(Request::Trigger {
+            input: "volume".to_string(),
+            gesture: Gesture::Short,
+            turn: None,
+        })
+        .await;
+    assert!(matches!(response, Response::Error { .. }), "{:?}", response);
+
+    let response = control.request(Request::Bindings).await;
+    let dump = serde_json::to_string(&response).unwrap();
+    assert!(dump.contains("\"password\":\"********\""), "{}", dump);
+    assert!(!dump.contains("hunter2"), "{}", dump);
+
+    // A failing line is given up, the others carry on.
+    sim.fail(7, "read failed").unwrap();
+    assert!(eventually(|| !sim.requested().contains(&7)).await);
+    press(&sim, 5, 5_000_000_000, 20).await;
+    assert!(eventually(|| sim.output(6) == Some(false)).await);
+
+    raise(Signal::SIGTERM).unwrap();
+    event_loop.await.unwrap();
+    assert_eq!(sim.requested(), HashSet::new());
+    assert_eq!(duty_cycle(), "100000");
+    fs::remove_dir_all(&pwm_root).unwrap();
+}
+
+#[tokio::test]
+async fn a_reload_leaves_unchanged_levels_alone() {
+    let (text, pwm_root) = config("reload");
+    let cfg: AppConfig = toml::from_str(&text).unwrap();
+    let sim = SimBackend::new(16);
+    let ctx = Context::new(Box::new(sim.clone()), Box::new(MockPower::default()));
+    let mut daemon = Daemon::start(cfg, ctx).await.unwrap();
+    let control = daemon.control();
+    // The event loop only runs for as long as the request takes.
+    tokio::select! {
+        response = control.request(Request::Set { output: 6, value: true }) => {
+            assert!(matches!(response, Response::Ok), "{:?}", response);
+        }
+        result = daemon.tick(Path::new("/nonexistent")) => panic!("{:?}", result),
+    }
+    assert_eq!(sim.output(6), Some(true));
+
+    let path = pwm_root.join("radio.toml");
+    let reloads = [
+        (r#"gpio6 = { action = "setoff", on_exit = true }"#, true),
+        (r#"gpio6 = "seton""#, true),
+        (r#"gpio6 = "setoff""#, false),
+    ];
+    for (binding, level) in reloads.iter() {
+        fs::write(&path, text.replace(r#"gpio6 = "setoff""#, binding)).unwrap();
+        daemon.reload(&path).await;
+        assert_eq!(sim.output(6), Some(*level), "{}", binding);
+    }
+    fs::remove_dir_all(&pwm_root).unwrap();
+}